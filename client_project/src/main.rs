@@ -2,8 +2,8 @@
 
 // Методы
 static METHODS: &'static [&str] = &["GET", "POST", "PUT", "DELETE"];
-static GET_COMMANDS: &'static [&str] = &["users", "groups", "group/target_by_id"];
-static POST_COMMANDS: &'static [&str] = &["user/create", "group/create", "group/join", "group/unadmin", "group/make_admin", "group/quit", "group/secret_santa"];
+static GET_COMMANDS: &'static [&str] = &["users", "groups", "group/target_by_id", "group/non_participants", "group/assignments", "group/wait_closed", "group/giver", "group/cycle_info", "group/graph", "group/snapshot", "reveal", "user", "group/members", "healthz", "health", "group/stats", "user/groups", "group/validate", "group/contains", "metrics"];
+static POST_COMMANDS: &'static [&str] = &["user/create", "group/create", "group/join", "group/unadmin", "group/make_admin", "group/quit", "group/close", "group/reopen", "group/secret_santa", "group/merge", "group/import", "group/reveal_token", "group/kick", "group/undo", "group/exclude", "user/wishlist", "user/rename", "group/set_budget", "group/rename", "group/assignments", "group/transfer_admin", "group/reassign", "group/remove_member_from_draw", "group/set_unique_names", "users/create_batch", "group/invite", "group/set_private"];
 static PUT_COMMANDS: &'static [&str] = &["user/update"];
 static DELETE_COMMANDS: &'static [&str] = &["user/delete", "group/delete"];
 
@@ -0,0 +1,2070 @@
+// Обработчики запросов с JSON телом: принимают объект из тела и состояние сервера,
+// маршруты в main только разбирают тело и передают его сюда
+use std::collections::hash_map::Entry;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use super::*;
+
+pub fn user_create(input_obj: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let name: String = get_field(input_obj, "name")?;
+    let guest = get_optional_bool_field(input_obj, "guest")?;
+    Ok(match normalize_name(&name)
+    {
+        Err(msg) => response_error(&msg),
+        Ok(name) =>
+        {
+            let id = new_user_id();
+            let token = new_token();
+            let mut guard = write_state(state);
+            guard.users.insert(id, name);
+            guard.user_tokens.insert(token.clone(), id);
+            if guest
+            {
+                guard.guests.insert(id, Instant::now() + guest_ttl());
+            }
+
+            response_created(json!({"id": id, "guest": guest, "token": token}), &format!("/user/{}", id))
+        }
+    })
+}
+
+pub fn users_create_batch(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let names: Vec<String> = object.get("names")
+        .ok_or_else(|| missing_field("names"))?
+        .as_array()
+        .ok_or_else(|| wrong_field_type("names", "an array"))?
+        .iter()
+        .map(|name| parse_value(name, "names"))
+        .collect::<tide::Result<_>>()?;
+
+    // Все имена проверяются до создания первого пользователя: либо создаются все, либо никто
+    let mut normalized = Vec::with_capacity(names.len());
+    for (index, name) in names.iter().enumerate()
+    {
+        match normalize_name(name)
+        {
+            Ok(name) => normalized.push(name),
+            Err(msg) => return Ok(response_error(&format!("names[{}]: {}", index, msg))),
+        }
+    }
+
+    // Диапазон ID выдается одним обращением к счетчику, поэтому ID пользователей идут подряд
+    let ids = new_user_ids(normalized.len());
+    let mut guard = write_state(state);
+    let users: Vec<Value> = normalized.into_iter().zip(ids)
+        .map(|(name, id)|
+        {
+            let token = new_token();
+            guard.user_tokens.insert(token.clone(), id);
+            let user = json!({"name": name, "id": id, "token": token});
+            guard.users.insert(id, name);
+            user
+        })
+        .collect();
+    Ok(response_data(json!(users)))
+}
+
+pub fn group_create(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let creator_id: Id = get_field(object, "creator_id")?;
+    let name: String = get_field(object, "name")?;
+    let members: Vec<Id> = get_list_field(object, "members")?;
+    let unique_names = get_optional_bool_field(object, "unique_names")?;
+    let private = get_optional_bool_field(object, "private")?;
+
+    let name = match normalize_name(&name)
+    {
+        Ok(name) => name,
+        Err(msg) => return Ok(response_error(&msg)),
+    };
+
+    // ID группы, которая не будет создана, просто пропускается
+    let id = new_group_id();
+    let mut guard = write_state(state);
+    if !guard.users.contains_key(&creator_id) || members.iter().any(|id| !guard.users.contains_key(id))
+    {
+        return Ok(response_not_found("no such user"));
+    }
+    if let Some(response) = check_unique_names(unique_names, members.iter().copied().chain([creator_id]), None, &guard)
+    {
+        return Ok(response);
+    }
+
+    let mut group = GroupProps::new(name);
+    group.unique_names = unique_names;
+    group.is_private = private;
+    guard.groups.insert(id, group);
+    guard.user_groups.insert(
+        UserGroupId
+        {
+            user_id: creator_id,
+            group_id: id,
+        },
+        UserGroupProps::new(Access::Admin)
+    );
+    for user_id in members
+    {
+        guard.user_groups.entry(UserGroupId{user_id, group_id: id})
+            .or_insert_with(|| UserGroupProps::new(Access::User));
+    }
+    Ok(response_created(json!({"group_id": id}), &format!("/group/members?group_id={}", id)))
+}
+
+pub fn group_join(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let user_id = get_field(object, "user_id")?;
+    let given_group_id: Option<Id> = get_optional_field(object, "group_id")?;
+    let invite_token: Option<String> = get_optional_field(object, "invite_token")?;
+    let upsert = get_optional_bool_field(object, "upsert")?;
+
+    let mut guard = write_state(state);
+    // Приглашение само указывает группу; group_id рядом с ним должен с ней совпадать
+    let group_id = match &invite_token
+    {
+        None => given_group_id.ok_or_else(|| missing_field("group_id"))?,
+        Some(token) => match guard.invites.get(token).filter(|invite| !invite.is_expired())
+        {
+            None => return Ok(response_not_found("no such invite")),
+            Some(invite) if given_group_id.is_some_and(|given| given != invite.group_id) =>
+                return Ok(response_error("invite is for another group")),
+            Some(invite) => invite.group_id,
+        },
+    };
+    // В группе с уникальными именами новичок не может носить имя уже вступившего участника
+    let name_error = match guard.groups.get(&group_id)
+    {
+        Some(group) if !does_user_belong_to_group(user_id, group_id, &guard.user_groups) =>
+            check_unique_names(group.unique_names, get_member_ids(group_id, &guard.user_groups), guard.users.get(&user_id).map(|name| (user_id, name)), &guard),
+        _ => None,
+    };
+    Ok(match guard.groups.get(&group_id)
+    {
+        None => response_not_found("no such group"),
+        Some(group) =>
+        {
+            if group.is_closed
+            {
+                response_error("group is closed")
+            }
+            else if group.is_private && invite_token.is_none()
+            {
+                response_error("group is private, an invite is required")
+            }
+            else
+            {
+                // Проверка пользователя и вставка должны идти под одной блокировкой,
+                // иначе параллельный /user/delete оставит участника без пользователя
+                if !guard.users.contains_key(&user_id)
+                {
+                    response_not_found("no such user")
+                }
+                else if let Some(response) = name_error
+                {
+                    response
+                }
+                else
+                {
+                    match guard.user_groups.entry(UserGroupId{user_id, group_id})
+                    {
+                        // Повторное вступление с upsert ничего не меняет
+                        Entry::Occupied(_) if upsert => response_empty(),
+                        Entry::Occupied(_) => response_error("user already in group"),
+                        Entry::Vacant(entry) =>
+                        {
+                            entry.insert(UserGroupProps::new(Access::User));
+                            guard.last_actions.insert(group_id, GroupAction::Joined(user_id));
+                            if let Some(token) = &invite_token
+                            {
+                                if guard.invites[token].single_use
+                                {
+                                    guard.invites.remove(token);
+                                }
+                            }
+                            response_empty()
+                        }
+                    }
+                }
+            }
+        },
+    })
+}
+
+pub fn group_unadmin(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id = get_field(object, "admin_id")?;
+    let group_id = get_field(object, "group_id")?;
+
+    let mut guard = write_state(state);
+    if !guard.groups.contains_key(&group_id)
+    {
+        return Ok(response_not_found("no such group"));
+    }
+    let user_group_id = UserGroupId{user_id: admin_id, group_id};
+    Ok(match guard.user_groups.get(&user_group_id)
+    {
+        None => response_error("user does not belong to this group"),
+        Some(user_group_props) =>
+        {
+            if user_group_props.access_level != Access::Admin
+            {
+                response_error("This user is not an admin.")
+            }
+            else
+            {
+                if count_admins(group_id, &guard.user_groups) < 2
+                {
+                    response_error("It is impossible to remove the last admin in a group. You can appoint a new admin and repeat or delete the whole group.")
+                }
+                else
+                {
+                    guard.user_groups.get_mut(&user_group_id).unwrap().access_level = Access::User;
+                    guard.last_actions.insert(group_id, GroupAction::Unadmined(admin_id));
+                    response_empty()
+                }
+            }
+        }
+    })
+}
+
+pub fn group_delete(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id = get_field(object, "admin_id")?;
+    let group_id = get_field(object, "group_id")?;
+
+    let mut guard = write_state(state);
+    if !guard.groups.contains_key(&group_id)
+    {
+        return Ok(response_not_found("no such group"));
+    }
+    Ok(match guard.user_groups.get(&UserGroupId{user_id: admin_id, group_id})
+    {
+        None => response_error("user does not belong to this group"),
+        Some(user_group_props) =>
+        {
+            if user_group_props.access_level != Access::Admin
+            {
+                response_error("This user is not an admin.")
+            }
+            else
+            {
+                // Before delete group, we need to delete all users from this group
+                guard.user_groups.retain(|user_group_id, _|
+                    {
+                        user_group_id.group_id != group_id
+                    });
+                guard.reveal_tokens.retain(|_, user_group_id| user_group_id.group_id != group_id);
+                guard.invites.retain(|_, invite| invite.group_id != group_id);
+                guard.digests.remove(&group_id);
+                guard.last_actions.remove(&group_id);
+                guard.exclusions.remove(&group_id);
+                guard.groups.remove(&group_id);
+                response_empty()
+            }
+        }
+    })
+}
+
+pub fn group_merge(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let source_id: Id = get_field(object, "source_group_id")?;
+    let target_id: Id = get_field(object, "target_group_id")?;
+
+    let mut guard = write_state(state);
+    Ok(if source_id == target_id
+    {
+        response_error("cannot merge a group into itself")
+    }
+    else if !guard.groups.contains_key(&source_id) || !guard.groups.contains_key(&target_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, source_id, &guard.user_groups)
+        || !does_user_belong_to_group(admin_id, target_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, source_id, &guard.user_groups) || !is_admin(admin_id, target_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if guard.groups[&source_id].is_closed || guard.groups[&target_id].is_closed
+    {
+        response_error("group is closed")
+    }
+    else if let Some(response) = check_unique_names(guard.groups[&target_id].unique_names,
+        get_member_ids(source_id, &guard.user_groups).chain(get_member_ids(target_id, &guard.user_groups)), None, &guard)
+    {
+        response
+    }
+    else
+    {
+        let moved: Vec<(Id, Access)> = guard.user_groups.iter()
+            .filter(|(key, _)| key.group_id == source_id)
+            .map(|(key, props)| (key.user_id, props.access_level.clone()))
+            .collect();
+        guard.user_groups.retain(|key, _| key.group_id != source_id);
+        // Участник обеих групп остается один раз, с более высокими правами
+        for (user_id, access_level) in moved
+        {
+            let props = guard.user_groups.entry(UserGroupId{user_id, group_id: target_id})
+                .or_insert_with(|| UserGroupProps::new(Access::User));
+            if access_level == Access::Admin
+            {
+                props.access_level = Access::Admin;
+            }
+        }
+        guard.groups.remove(&source_id);
+        guard.invites.retain(|_, invite| invite.group_id != source_id);
+        guard.last_actions.remove(&source_id);
+        if let Some(excluded) = guard.exclusions.remove(&source_id)
+        {
+            guard.exclusions.entry(target_id).or_default().extend(excluded);
+        }
+        guard.last_actions.remove(&target_id);
+        response_empty()
+    })
+}
+
+pub fn group_make_admin(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let group_id: Id = get_field(object, "group_id")?;
+    let member_id: Id = get_field(object, "member_id")?;
+    let admin_id: Id = get_field(object, "admin_id")?;
+
+    let mut guard = write_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(member_id, group_id, &guard.user_groups)
+    {
+        response_error("user isn't a member of the group")
+    }
+    else if is_admin(member_id, group_id, &guard.user_groups)
+    {
+        response_error("user is already an admin")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+        || !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("admin_id isn't an actual admin's ID")
+    }
+    else {
+        // Назначение участника в закрытой группе сохраняется
+        guard.user_groups.get_mut(&UserGroupId{user_id: member_id, group_id}).unwrap().access_level = Access::Admin;
+        guard.last_actions.insert(group_id, GroupAction::MadeAdmin(member_id));
+        response_empty()
+    })
+}
+
+// Передача прав администратора под одной блокировкой: администраторов в группе не становится меньше одного
+pub fn group_transfer_admin(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let target_id: Id = get_field(object, "target_id")?;
+
+    let mut guard = write_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if !does_user_belong_to_group(target_id, group_id, &guard.user_groups)
+    {
+        response_error("user isn't a member of the group")
+    }
+    else if target_id == admin_id
+    {
+        response_error("cannot transfer admin rights to yourself")
+    }
+    else
+    {
+        guard.user_groups.get_mut(&UserGroupId{user_id: target_id, group_id}).unwrap().access_level = Access::Admin;
+        guard.user_groups.get_mut(&UserGroupId{user_id: admin_id, group_id}).unwrap().access_level = Access::User;
+        // Права поменялись, прежнее действие (например MadeAdmin) отменять уже нельзя
+        guard.last_actions.remove(&group_id);
+        response_empty()
+    })
+}
+
+pub fn group_quit(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let group_id: Id = get_field(object, "group_id")?;
+    let user_id: Id = get_field(object, "user_id")?;
+
+    let mut guard = write_state(state);
+    if !guard.groups.contains_key(&group_id)
+    {
+        return Ok(response_not_found("no such group"));
+    }
+    let user_group_id = UserGroupId{user_id, group_id};
+    Ok(match guard.user_groups.get(&user_group_id)
+    {
+        None => response_error("user does not belong to this group"),
+        Some(user_group_props) =>
+        {
+            if user_group_props.access_level == Access::Admin && count_admins(group_id, &guard.user_groups) < 2
+            {
+                response_error("user is only one Admin in this group")
+            }
+            else
+            {
+                if guard.groups[&group_id].is_closed
+                {
+                    response_error("group is closed")
+                }
+                else
+                {
+                    let access_level = user_group_props.access_level.clone();
+                    guard.user_groups.remove(&user_group_id);
+                    drop_exclusions(&mut guard.exclusions, group_id, user_id);
+                    guard.last_actions.insert(group_id, GroupAction::Quit(user_id, access_level));
+                    response_empty()
+                }
+            }
+        }
+    })
+}
+
+pub fn group_set_budget(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let budget: Option<u32> = get_optional_field(object, "budget")?;
+
+    let mut guard = write_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if guard.groups[&group_id].is_closed
+    {
+        response_error("group is closed")
+    }
+    else
+    {
+        // Без budget ограничение снимается
+        guard.groups.get_mut(&group_id).unwrap().budget = budget;
+        response_empty()
+    })
+}
+
+pub fn group_rename(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let name: String = get_field(object, "name")?;
+
+    let mut guard = write_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else
+    {
+        match normalize_name(&name)
+        {
+            Err(msg) => response_error(&msg),
+            Ok(name) =>
+            {
+                guard.groups.get_mut(&group_id).unwrap().name = name;
+                response_empty()
+            }
+        }
+    })
+}
+
+pub fn group_invite(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let ttl_seconds: Option<u64> = get_optional_field(object, "ttl_seconds")?;
+
+    let mut guard = write_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if guard.groups[&group_id].is_closed
+    {
+        response_error("group is closed")
+    }
+    else if ttl_seconds == Some(0)
+    {
+        response_error("ttl_seconds must be positive")
+    }
+    else
+    {
+        // Без срока приглашение одноразовое, со сроком - многоразовое до его истечения
+        let invite = Invite
+        {
+            group_id,
+            single_use: ttl_seconds.is_none(),
+            expires_at: ttl_seconds.map(|ttl| unix_time().saturating_add(ttl)),
+        };
+        // Истекшие приглашения больше не нужны
+        guard.invites.retain(|_, invite| !invite.is_expired());
+        let token = new_token();
+        let expires_at = invite.expires_at;
+        guard.invites.insert(token.clone(), invite);
+        response_data(json!({"invite_token": token, "expires_at": expires_at}))
+    })
+}
+
+pub fn group_set_private(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let private = get_bool_field(object, "private")?;
+
+    let mut guard = write_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else
+    {
+        guard.groups.get_mut(&group_id).unwrap().is_private = private;
+        response_empty()
+    })
+}
+
+pub fn group_set_unique_names(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let unique_names = get_bool_field(object, "unique_names")?;
+
+    let mut guard = write_state(state);
+    if !guard.groups.contains_key(&group_id)
+    {
+        return Ok(response_not_found("no such group"));
+    }
+    if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        return Ok(response_error("user does not belong to this group"));
+    }
+    if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        return Ok(response_error("This user is not an admin."));
+    }
+    // Включить режим можно, только если среди нынешних участников нет тезок
+    if let Some(response) = check_unique_names(unique_names, get_member_ids(group_id, &guard.user_groups), None, &guard)
+    {
+        return Ok(response);
+    }
+    guard.groups.get_mut(&group_id).unwrap().unique_names = unique_names;
+    Ok(response_empty())
+}
+
+pub fn group_exclude(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let user_a: Id = get_field(object, "user_a")?;
+    let user_b: Id = get_field(object, "user_b")?;
+
+    let mut guard = write_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if !does_user_belong_to_group(user_a, group_id, &guard.user_groups)
+        || !does_user_belong_to_group(user_b, group_id, &guard.user_groups)
+    {
+        response_error("user isn't a member of the group")
+    }
+    else if user_a == user_b
+    {
+        response_error("cannot exclude a user from themselves")
+    }
+    else if is_drawn(group_id, &guard.user_groups)
+    {
+        response_error("secret santa already started")
+    }
+    else
+    {
+        guard.exclusions.entry(group_id).or_default().insert(exclusion_pair(user_a, user_b));
+        response_empty()
+    })
+}
+
+pub fn group_kick(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let target_id: Id = get_field(object, "target_id")?;
+
+    let mut guard = write_state(state);
+    let target = UserGroupId{user_id: target_id, group_id};
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if admin_id == target_id
+    {
+        response_error("cannot kick yourself, use /group/quit")
+    }
+    else if !guard.user_groups.contains_key(&target)
+    {
+        response_error("user isn't a member of the group")
+    }
+    else if guard.groups[&group_id].is_closed
+    {
+        response_error("group is closed")
+    }
+    else
+    {
+        // Выгнать последнего администратора нельзя: admin_id сам администратор
+        let access_level = guard.user_groups.remove(&target).unwrap().access_level;
+        drop_exclusions(&mut guard.exclusions, group_id, target_id);
+        guard.last_actions.insert(group_id, GroupAction::Kicked(target_id, access_level));
+        response_empty()
+    })
+}
+
+pub fn group_undo(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+
+    let mut guard = write_state(state);
+    if !guard.groups.contains_key(&group_id)
+    {
+        return Ok(response_not_found("no such group"));
+    }
+    if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        return Ok(response_error("user does not belong to this group"));
+    }
+    if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        return Ok(response_error("This user is not an admin."));
+    }
+    if guard.groups[&group_id].is_closed
+    {
+        return Ok(response_error("group is closed"));
+    }
+    // Изменения, сделанные после запомненного действия (например, удаление
+    // пользователя), могут сделать его отмену невозможной
+    Ok(match guard.last_actions.remove(&group_id)
+    {
+        None => response_error("nothing to undo"),
+        Some(GroupAction::Joined(user_id)) =>
+        {
+            if !does_user_belong_to_group(user_id, group_id, &guard.user_groups)
+            {
+                response_error("user does not belong to this group")
+            }
+            else if is_admin(user_id, group_id, &guard.user_groups) && count_admins(group_id, &guard.user_groups) < 2
+            {
+                response_error("user is only one Admin in this group")
+            }
+            else
+            {
+                guard.user_groups.remove(&UserGroupId{user_id, group_id});
+                response_empty()
+            }
+        }
+        Some(GroupAction::Quit(user_id, access_level)) | Some(GroupAction::Kicked(user_id, access_level)) =>
+        {
+            if !guard.users.contains_key(&user_id)
+            {
+                response_not_found("no such user")
+            }
+            else if does_user_belong_to_group(user_id, group_id, &guard.user_groups)
+            {
+                response_error("user already in group")
+            }
+            else if let Some(response) = check_unique_names(guard.groups[&group_id].unique_names,
+                get_member_ids(group_id, &guard.user_groups), Some((user_id, &guard.users[&user_id])), &guard)
+            {
+                response
+            }
+            else
+            {
+                guard.user_groups.insert(UserGroupId{user_id, group_id}, UserGroupProps::new(access_level));
+                response_empty()
+            }
+        }
+        Some(GroupAction::MadeAdmin(user_id)) =>
+        {
+            if !does_user_belong_to_group(user_id, group_id, &guard.user_groups)
+            {
+                response_error("user does not belong to this group")
+            }
+            else if count_admins(group_id, &guard.user_groups) < 2
+            {
+                response_error("user is only one Admin in this group")
+            }
+            else
+            {
+                guard.user_groups.get_mut(&UserGroupId{user_id, group_id}).unwrap().access_level = Access::User;
+                response_empty()
+            }
+        }
+        Some(GroupAction::Unadmined(user_id)) =>
+        {
+            match guard.user_groups.get_mut(&UserGroupId{user_id, group_id})
+            {
+                None => response_error("user does not belong to this group"),
+                Some(props) =>
+                {
+                    props.access_level = Access::Admin;
+                    response_empty()
+                }
+            }
+        }
+    })
+}
+
+pub fn group_close(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+
+    let mut guard = write_state(state);
+    let members = guard.user_groups.keys().filter(|key| key.group_id == group_id).count();
+    let min_members = min_group_size();
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if guard.groups[&group_id].is_closed
+    {
+        response_error("group is already closed")
+    }
+    else if members < min_members
+    {
+        response_group_too_small(members, min_members)
+    }
+    else
+    {
+        // Состав группы больше не меняется, жеребьевка - отдельным /group/secret_santa
+        guard.groups.get_mut(&group_id).unwrap().is_closed = true;
+        guard.last_actions.remove(&group_id);
+        response_empty()
+    })
+}
+
+pub fn group_reopen(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+
+    let mut guard = write_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if !guard.groups[&group_id].is_closed
+    {
+        response_error("group is not closed")
+    }
+    else
+    {
+        // Кто уже узнал свое назначение, мог начать готовить подарок
+        let mut revealed: Vec<Id> = Vec::new();
+        for (key, props) in guard.user_groups.iter_mut().filter(|(key, _)| key.group_id == group_id)
+        {
+            if props.revealed
+            {
+                revealed.push(key.user_id);
+            }
+            props.santa_id = None;
+            props.revealed = false;
+            props.sits_out = false;
+        }
+        revealed.sort_unstable();
+        guard.groups.get_mut(&group_id).unwrap().is_closed = false;
+        guard.digests.remove(&group_id);
+        guard.reveal_tokens.retain(|_, user_group_id| user_group_id.group_id != group_id);
+        response_data(json!({"warning": !revealed.is_empty(), "revealed": revealed}))
+    })
+}
+
+// Снятие участника закрытой группы с жеребьевки: он остается в группе, но не дарит и не получает
+pub fn group_remove_member_from_draw(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let member_id: Id = get_field(object, "member_id")?;
+
+    let mut guard = write_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if !does_user_belong_to_group(member_id, group_id, &guard.user_groups)
+    {
+        response_error("user isn't a member of the group")
+    }
+    else if !guard.groups[&group_id].is_closed
+    {
+        response_error("group is not closed")
+    }
+    else if is_drawn(group_id, &guard.user_groups)
+    {
+        response_error("secret santa already started")
+    }
+    else
+    {
+        // Исключения с ним больше ничего не ограничивают
+        guard.user_groups.get_mut(&UserGroupId{user_id: member_id, group_id}).unwrap().sits_out = true;
+        drop_exclusions(&mut guard.exclusions, group_id, member_id);
+        response_empty()
+    })
+}
+
+pub fn group_secret_santa(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let group_id: Id = get_field(object, "group_id")?;
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let max_cycle_length: Option<usize> = get_optional_field(object, "max_cycle_length")?;
+    let forced = get_pairs_field(object, "forced")?;
+    // Без seed берется случайный; он возвращается в ответе, чтобы жеребьевку можно было повторить
+    let seed: u64 = get_optional_field(object, "seed")?.unwrap_or_else(rand::random);
+
+    let mut guard = write_state(state);
+    if !guard.groups.contains_key(&group_id)
+    {
+        return Ok(response_not_found("no such group"));
+    }
+    Ok(match guard.user_groups.get(&UserGroupId{user_id: admin_id, group_id})
+    {
+        None => response_error("user does not belong to this group"),
+        Some(user_group_props) =>
+        {
+            if user_group_props.access_level != Access::Admin
+            {
+                response_error("its not admin")
+            }
+            else if is_drawn(group_id, &guard.user_groups)
+            {
+                // Повторный запуск не должен менять уже выданные назначения
+                response_conflict("secret santa already started", json!(get_assignments(group_id, &guard.user_groups)))
+            }
+            else if !guard.groups[&group_id].is_closed
+            {
+                response_error("group is not closed")
+            }
+            else
+            {
+                let mut group: Vec<Id> = guard.user_groups.iter().filter_map(|(key, props)|
+                    match key.group_id == group_id && !props.sits_out
+                    {
+                        true => Some(key.user_id),
+                        false => None,
+                    }
+                ).collect();
+                // Группа могла быть закрыта до того, как минимум увеличили
+                let min_members = min_group_size();
+                if group.len() < min_members
+                {
+                    return Ok(response_group_too_small(group.len(), min_members));
+                }
+                // Цепочки строятся по порядку участников, поэтому случайный
+                // порядок дает случайное назначение. Сортировка нужна, чтобы
+                // при том же seed и составе получилось то же назначение.
+                group.sort_unstable();
+                group.shuffle(&mut StdRng::seed_from_u64(seed));
+                let excluded = guard.exclusions.get(&group_id).cloned().unwrap_or_default();
+                let santas = match (max_cycle_length, forced.is_empty())
+                {
+                    (Some(_), _) if !excluded.is_empty() => Err("max_cycle_length cannot be combined with exclusions"),
+                    (Some(_), false) => Err("max_cycle_length cannot be combined with forced"),
+                    (Some(max_cycle_length), true) => get_secret_santas_with_max_cycle(&group, max_cycle_length)
+                        .ok_or("cannot split the group into cycles no longer than max_cycle_length"),
+                    (None, true) if excluded.is_empty() => Ok(get_secret_santas(&group)),
+                    // Заданные пары и исключения - ограничения одного и того же поиска назначения
+                    (None, _) => get_secret_santas_with_exclusions(&group, &forced, &excluded),
+                };
+                match santas
+                {
+                    Err(msg) => response_error(msg),
+                    Ok(santas) =>
+                    {
+                        for i in 0..group.len()
+                        {
+                            guard.user_groups.get_mut(&UserGroupId{user_id: group[i], group_id}).unwrap().santa_id = Some(santas[i]);
+                        }
+                        // Ошибка в построении назначений не должна дойти до участников
+                        if let Err(msg) = validate_assignment(group_id, &guard.user_groups, &excluded)
+                        {
+                            for user_id in &group
+                            {
+                                guard.user_groups.get_mut(&UserGroupId{user_id: *user_id, group_id}).unwrap().santa_id = None;
+                            }
+                            return Ok(response_internal_error(&format!("invalid assignment: {}", msg)));
+                        }
+                        let digest = get_assignment_digest(group_id, &guard.user_groups);
+                        guard.digests.insert(group_id, digest.clone());
+                        GROUP_CLOSED.notify(usize::MAX);
+                        response_data(json!({"digest": digest, "seed": seed}))
+                    }
+                }
+            }
+        }
+    })
+}
+
+// Все назначения группы с именами, только для администратора
+pub fn group_assignments(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+
+    let guard = read_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if !is_drawn(group_id, &guard.user_groups)
+    {
+        response_error("secret santa has not started yet")
+    }
+    else
+    {
+        let mut pairs: Vec<(Id, Id)> = guard.user_groups.iter()
+            .filter(|(key, _)| key.group_id == group_id)
+            .filter_map(|(key, props)| props.santa_id.map(|santa_id| (key.user_id, santa_id)))
+            .collect();
+        pairs.sort_unstable();
+        let assignments: Vec<Value> = pairs.iter()
+            .map(|(giver_id, recipient_id)| json!({
+                "giver_id": giver_id,
+                "giver_name": guard.users.get(giver_id),
+                "recipient_id": recipient_id,
+                "recipient_name": guard.users.get(recipient_id),
+            }))
+            .collect();
+        response_data(json!(assignments))
+    })
+}
+
+// Выход участника из группы после жеребьевки с сохранением как можно большего числа назначений
+pub fn group_reassign(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let leaving_id: Id = get_field(object, "leaving_id")?;
+
+    let mut guard = write_state(state);
+    if !guard.groups.contains_key(&group_id)
+    {
+        return Ok(response_not_found("no such group"));
+    }
+    if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+    {
+        return Ok(response_error("user does not belong to this group"));
+    }
+    if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        return Ok(response_error("This user is not an admin."));
+    }
+    if !is_drawn(group_id, &guard.user_groups)
+    {
+        return Ok(response_error("secret santa has not started yet"));
+    }
+    if !does_user_belong_to_group(leaving_id, group_id, &guard.user_groups)
+    {
+        return Ok(response_error("user isn't a member of the group"));
+    }
+    if is_admin(leaving_id, group_id, &guard.user_groups) && count_admins(group_id, &guard.user_groups) < 2
+    {
+        return Ok(response_error("user is only one Admin in this group"));
+    }
+    // Снятый с жеребьевки ни с кем не связан, назначения остальных не меняются
+    if guard.user_groups[&UserGroupId{user_id: leaving_id, group_id}].sits_out
+    {
+        guard.user_groups.remove(&UserGroupId{user_id: leaving_id, group_id});
+        drop_exclusions(&mut guard.exclusions, group_id, leaving_id);
+        let digest = guard.digests.get(&group_id).cloned();
+        return Ok(response_data(json!({"changed": [], "digest": digest})));
+    }
+    let targets: HashMap<Id, Id> = guard.user_groups.iter()
+        .filter(|(key, _)| key.group_id == group_id)
+        .filter_map(|(key, props)| props.santa_id.map(|santa_id| (key.user_id, santa_id)))
+        .collect();
+    // После выхода в жеребьевке должно остаться столько участников, сколько нужно для нее
+    let min_members = min_group_size();
+    if targets.len() - 1 < min_members
+    {
+        return Ok(response_group_too_small(targets.len() - 1, min_members));
+    }
+    let excluded = guard.exclusions.get(&group_id).cloned().unwrap_or_default();
+    Ok(match repair_after_departure(&targets, leaving_id, &excluded)
+    {
+        None => response_error("exclusions do not allow keeping the draw, reopen the group instead"),
+        Some(changes) =>
+        {
+            // Новые назначения проверяются на копии, до изменения данных
+            let mut repaired: HashMap<UserGroupId, UserGroupProps> = guard.user_groups.iter()
+                .filter(|(key, _)| key.group_id == group_id && key.user_id != leaving_id)
+                .map(|(key, props)| (key.clone(), props.clone()))
+                .collect();
+            for (giver, receiver) in &changes
+            {
+                repaired.get_mut(&UserGroupId{user_id: *giver, group_id}).unwrap().santa_id = Some(*receiver);
+            }
+            if let Err(msg) = validate_assignment(group_id, &repaired, &excluded)
+            {
+                return Ok(response_internal_error(&format!("invalid assignment: {}", msg)));
+            }
+            guard.user_groups.remove(&UserGroupId{user_id: leaving_id, group_id});
+            drop_exclusions(&mut guard.exclusions, group_id, leaving_id);
+            guard.reveal_tokens.retain(|_, user_group_id| *user_group_id != UserGroupId{user_id: leaving_id, group_id});
+            // Кто узнал прежнее назначение, должен узнать новое
+            for (giver, receiver) in &changes
+            {
+                let props = guard.user_groups.get_mut(&UserGroupId{user_id: *giver, group_id}).unwrap();
+                props.santa_id = Some(*receiver);
+                props.revealed = false;
+            }
+            let digest = get_assignment_digest(group_id, &guard.user_groups);
+            guard.digests.insert(group_id, digest.clone());
+            let mut changed: Vec<Id> = changes.iter().map(|(giver, _)| *giver).collect();
+            changed.sort_unstable();
+            response_data(json!({"changed": changed, "digest": digest}))
+        }
+    })
+}
+
+pub fn group_reveal_token(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let admin_id: Id = get_field(object, "admin_id")?;
+    let group_id: Id = get_field(object, "group_id")?;
+    let user_id: Id = get_field(object, "user_id")?;
+
+    let mut guard = write_state(state);
+    Ok(if !guard.groups.contains_key(&group_id)
+    {
+        response_not_found("no such group")
+    }
+    else if !does_user_belong_to_group(admin_id, group_id, &guard.user_groups)
+        || !does_user_belong_to_group(user_id, group_id, &guard.user_groups)
+    {
+        response_error("user does not belong to this group")
+    }
+    else if !is_admin(admin_id, group_id, &guard.user_groups)
+    {
+        response_error("This user is not an admin.")
+    }
+    else if !is_drawn(group_id, &guard.user_groups)
+    {
+        response_error("secret santa has not started yet")
+    }
+    else if guard.user_groups[&UserGroupId{user_id, group_id}].sits_out
+    {
+        response_error("user does not take part in the draw")
+    }
+    else
+    {
+        let token = new_token();
+        guard.reveal_tokens.insert(token.clone(), UserGroupId{user_id, group_id});
+        response_data(json!({"token": token}))
+    })
+}
+
+// Восстановление группы из снимка GET /group/snapshot на этом или другом сервере
+pub fn group_import(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    // Объект, который не разбирается как снимок, - 422, как у тела неверного формата
+    let snapshot: GroupSnapshot = serde_json::from_value(Value::Object(object.clone()))
+        .map_err(|error| tide::Error::from_str(422, error.to_string()))?;
+    if let Some(msg) = check_snapshot(&snapshot)
+    {
+        return Ok(response_error(msg));
+    }
+    if snapshot.unique_names
+    {
+        let names: Vec<String> = snapshot.members.iter().map(|member| normalize_name(&member.name).unwrap()).collect();
+        if let Some(taken) = find_duplicate_name(names.iter())
+        {
+            return Ok(name_taken_error(taken));
+        }
+    }
+
+    // Пользователи и группа получают новые ID на этом сервере
+    let new_ids: HashMap<Id, Id> = snapshot.members.iter().map(|member| member.id).zip(new_user_ids(snapshot.members.len())).collect();
+    let group_id = new_group_id();
+    let mut guard = write_state(state);
+    let mut tokens: Map<String, Value> = Map::new();
+    for member in &snapshot.members
+    {
+        let id = new_ids[&member.id];
+        let token = new_token();
+        guard.users.insert(id, normalize_name(&member.name).unwrap());
+        guard.user_tokens.insert(token.clone(), id);
+        if !member.wishlist.is_empty()
+        {
+            guard.wishlists.insert(id, member.wishlist.clone());
+        }
+        tokens.insert(member.id.to_string(), json!(token));
+    }
+    let name = normalize_name(&snapshot.name).unwrap_or_else(|_| default_group_name(group_id));
+    guard.groups.insert(group_id, GroupProps {
+        name,
+        is_closed: snapshot.closed,
+        unique_names: snapshot.unique_names,
+        is_private: snapshot.private,
+        budget: snapshot.budget,
+    });
+    if !snapshot.exclusions.is_empty()
+    {
+        let excluded = snapshot.exclusions.iter()
+            .map(|(user_a, user_b)| exclusion_pair(new_ids[user_a], new_ids[user_b]))
+            .collect();
+        guard.exclusions.insert(group_id, excluded);
+    }
+    for member in &snapshot.members
+    {
+        let mut props = UserGroupProps::new(if member.admin { Access::Admin } else { Access::User });
+        props.santa_id = member.santa_id.map(|santa_id| new_ids[&santa_id]);
+        props.sits_out = member.sits_out;
+        guard.user_groups.insert(UserGroupId{user_id: new_ids[&member.id], group_id}, props);
+    }
+    if is_drawn(group_id, &guard.user_groups)
+    {
+        let digest = get_assignment_digest(group_id, &guard.user_groups);
+        guard.digests.insert(group_id, digest);
+    }
+    let users: Map<String, Value> = new_ids.iter()
+        .map(|(old_id, new_id)| (old_id.to_string(), json!(new_id)))
+        .collect();
+    Ok(response_data(json!({"group_id": group_id, "users": users, "tokens": tokens})))
+}
+
+pub fn user_update(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let id : Id = get_field(object, "user_id")?;
+    let name: String = get_field(object, "name")?;
+    let mut guard = write_state(state);
+    Ok( if !guard.users.contains_key(&id)
+    {
+        response_not_found("No such id")
+    }
+    else
+    {
+        match normalize_name(&name)
+        {
+            Err(msg) => response_error(&msg),
+            Ok(name) =>
+            {
+                // Новое имя не должно совпасть с именем другого участника ни в одной группе с уникальными именами
+                let name_error = guard.user_groups.keys()
+                    .filter(|key| key.user_id == id)
+                    .find_map(|key| check_unique_names(guard.groups[&key.group_id].unique_names,
+                        get_member_ids(key.group_id, &guard.user_groups), Some((id, &name)), &guard));
+                match name_error
+                {
+                    Some(response) => response,
+                    None =>
+                    {
+                        guard.users.entry(id).and_modify(|k| *k = name);
+                        response_empty()
+                    }
+                }
+            }
+        }
+    })
+}
+
+pub fn user_wishlist(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let user_id: Id = get_field(object, "user_id")?;
+    let items: Vec<String> = get_list_field(object, "items")?;
+
+    Ok(if items.len() > WISHLIST_MAX_ITEMS
+    {
+        response_error(&format!("wishlist has more than {} items", WISHLIST_MAX_ITEMS))
+    }
+    else if items.iter().any(|item| item.is_empty() || item.chars().count() > WISHLIST_MAX_ITEM_LEN)
+    {
+        response_error(&format!("wishlist items must be 1 to {} characters long", WISHLIST_MAX_ITEM_LEN))
+    }
+    else
+    {
+        let mut guard = write_state(state);
+        if !guard.users.contains_key(&user_id)
+        {
+            response_not_found("no such user")
+        }
+        else
+        {
+            guard.wishlists.insert(user_id, items);
+            response_empty()
+        }
+    })
+}
+
+// "group 3" или "groups 1, 2" для сообщений об ошибках
+fn format_group_list(group_ids: &[Id]) -> String
+{
+    let ids: Vec<String> = group_ids.iter().map(Id::to_string).collect();
+    format!("{} {}", if ids.len() == 1 { "group" } else { "groups" }, ids.join(", "))
+}
+
+pub fn user_delete(object: &Map<String, Value>, state: &Arc<RwLock<DataBase>>) -> tide::Result<Response>
+{
+    let user_id = get_field(object, "user_id")?;
+    let mut guard = write_state(state);
+    Ok(match guard.users.get(&user_id)
+    {
+        None => response_not_found("This user does not exist."),
+        Some(_name) =>
+        {
+            if !guard.user_groups.is_empty()
+            {
+                let iter1 = guard.user_groups.iter();
+                let iter2 = guard.user_groups.iter();
+                let collection = iter1.filter(|&x| x.0.user_id == user_id);
+                let collect_copy = iter2.filter(|&x| x.0.user_id == user_id);
+                let closed_collect = collection.filter(|&x| guard.groups[&x.0.group_id].is_closed);
+                let free_collect = collect_copy.filter(|&x| !guard.groups[&x.0.group_id].is_closed);
+                let mut admin_flag = false;
+                let mut vec:Vec<Id> = Vec::new();
+                let mut delete_vec=Vec::new();
+                for x in free_collect
+                {
+                    if x.1.access_level == Access::Admin && count_admins(x.0.group_id, &guard.user_groups) == 1
+                    {
+                        admin_flag=true;
+                        vec.push(x.0.group_id);
+                    }
+                    else 
+                    {
+                        delete_vec.push(UserGroupId{user_id, group_id: x.0.group_id});
+                    }
+                }   
+                vec.sort_unstable();
+                if closed_collect.count() > 0
+                {
+                    for x in delete_vec
+                    {
+                        guard.user_groups.remove(&x);
+                        drop_exclusions(&mut guard.exclusions, x.group_id, user_id);
+                    }
+                    if admin_flag
+                    {
+                        let string = format!("User has closed groups. So he was deleted from opened groups, if he wasn't last admin. User cannot be deleted from {} because of last admin.", format_group_list(&vec));
+                        response_error_with_groups(&string, &vec)
+                    }
+                    else
+                    {
+                        response_error("User has closed groups. So he was deleted from opened groups.")
+                    }
+                }
+                else 
+                {
+                    for x in delete_vec
+                    {
+                        guard.user_groups.remove(&x);
+                        drop_exclusions(&mut guard.exclusions, x.group_id, user_id);
+                    }
+                    if !admin_flag
+                    {
+                        guard.users.remove(&user_id);
+                        guard.user_tokens.retain(|_, id| *id != user_id);
+                        guard.guests.remove(&user_id);
+                        guard.wishlists.remove(&user_id);
+                        response_empty()
+                    }
+                    else 
+                    {
+                        let string = format!("User cannot be deleted from {} because he is the last admin there.", format_group_list(&vec));
+                        response_error_with_groups(&string, &vec)
+                    }
+                }
+            }
+            else
+            {
+                guard.users.remove(&user_id);
+                guard.user_tokens.retain(|_, id| *id != user_id);
+                guard.guests.remove(&user_id);
+                guard.wishlists.remove(&user_id);
+                response_empty()
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    type Handler = fn(&Map<String, Value>, &Arc<RwLock<DataBase>>) -> tide::Result<Response>;
+
+    fn new_state() -> Arc<RwLock<DataBase>>
+    {
+        Arc::new(RwLock::new(DataBase::new()))
+    }
+
+    // Вызов обработчика, как из маршрута: ошибка через `?` дает код и {"error": ...}, как в error_body
+    fn call(handler: Handler, body: Value, state: &Arc<RwLock<DataBase>>) -> (u16, Value)
+    {
+        let _env = crate::tests::default_env();
+        let mut response = match handler(body.as_object().unwrap(), state)
+        {
+            Ok(response) => response,
+            Err(error) => return (error.status() as u16, json!({"error": error.to_string()})),
+        };
+        let bytes = futures::executor::block_on(response.take_body().into_bytes()).unwrap();
+        let body = if bytes.is_empty() { Value::Null } else { serde_json::from_slice(&bytes).unwrap() };
+        (response.status() as u16, body)
+    }
+
+    fn error(msg: &str) -> Value
+    {
+        json!({"error": msg})
+    }
+
+    fn create_user(state: &Arc<RwLock<DataBase>>, name: &str) -> Id
+    {
+        let (status, body) = call(user_create, json!({"name": name}), state);
+        assert_eq!(status, 201);
+        body["id"].as_u64().unwrap() as Id
+    }
+
+    fn create_group(state: &Arc<RwLock<DataBase>>, creator_id: Id, members: &[Id]) -> Id
+    {
+        let (status, body) = call(group_create, json!({"creator_id": creator_id, "name": "group", "members": members}), state);
+        assert_eq!(status, 201);
+        body["group_id"].as_u64().unwrap() as Id
+    }
+
+    // Группа из count участников, первый - администратор; возвращает ID группы и участников
+    fn group_of(state: &Arc<RwLock<DataBase>>, count: usize) -> (Id, Vec<Id>)
+    {
+        let users: Vec<Id> = (0..count).map(|index| create_user(state, &format!("user {}", index))).collect();
+        (create_group(state, users[0], &users[1..]), users)
+    }
+
+    fn access(state: &Arc<RwLock<DataBase>>, user_id: Id, group_id: Id) -> Option<bool>
+    {
+        read_state(state).user_groups.get(&UserGroupId{user_id, group_id}).map(|props| props.access_level == Access::Admin)
+    }
+
+    fn draw(state: &Arc<RwLock<DataBase>>, admin_id: Id, group_id: Id)
+    {
+        assert_eq!(call(group_close, json!({"admin_id": admin_id, "group_id": group_id}), state).0, 200);
+        assert_eq!(call(group_secret_santa, json!({"admin_id": admin_id, "group_id": group_id, "seed": 1}), state).0, 200);
+    }
+
+    #[test]
+    fn user_create_update_and_delete()
+    {
+        let state = new_state();
+        let id = create_user(&state, "  Ann ");
+        assert_eq!(read_state(&state).users[&id], "Ann");
+        assert_eq!(call(user_create, json!({"name": " "}), &state), (400, error("bad name")));
+
+        assert_eq!(call(user_update, json!({"user_id": id, "name": "Anna"}), &state).0, 200);
+        assert_eq!(read_state(&state).users[&id], "Anna");
+        assert_eq!(call(user_update, json!({"user_id": Id::MAX, "name": "Anna"}), &state), (404, error("No such id")));
+
+        assert_eq!(call(user_delete, json!({"user_id": id}), &state).0, 200);
+        assert!(!read_state(&state).users.contains_key(&id));
+        assert_eq!(call(user_delete, json!({"user_id": id}), &state), (404, error("This user does not exist.")));
+    }
+
+    #[test]
+    fn users_create_batch_is_all_or_nothing()
+    {
+        let state = new_state();
+        let (status, body) = call(users_create_batch, json!({"names": ["Ann", "Bob"]}), &state);
+        assert_eq!(status, 200);
+        let ids: Vec<Id> = body.as_array().unwrap().iter().map(|user| user["id"].as_u64().unwrap() as Id).collect();
+        assert_eq!(ids[1], ids[0] + 1);
+
+        let users_before = read_state(&state).users.len();
+        assert_eq!(call(users_create_batch, json!({"names": ["Eve", ""]}), &state).0, 400);
+        assert_eq!(read_state(&state).users.len(), users_before);
+    }
+
+    #[test]
+    fn user_wishlist_is_limited()
+    {
+        let state = new_state();
+        let id = create_user(&state, "Ann");
+        assert_eq!(call(user_wishlist, json!({"user_id": id, "items": ["book"]}), &state).0, 200);
+        assert_eq!(read_state(&state).wishlists[&id], vec!["book".to_string()]);
+        let items: Vec<String> = (0..=WISHLIST_MAX_ITEMS).map(|index| index.to_string()).collect();
+        assert_eq!(call(user_wishlist, json!({"user_id": id, "items": items}), &state).0, 400);
+        assert_eq!(call(user_wishlist, json!({"user_id": Id::MAX, "items": []}), &state), (404, error("no such user")));
+    }
+
+    #[test]
+    fn group_create_makes_creator_admin()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 3);
+        assert_eq!(access(&state, users[0], group_id), Some(true));
+        assert_eq!(access(&state, users[1], group_id), Some(false));
+        assert_eq!(call(group_create, json!({"creator_id": users[0], "name": "g", "members": [Id::MAX]}), &state),
+            (404, error("no such user")));
+    }
+
+    #[test]
+    fn group_join_and_quit()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 2);
+        let newcomer = create_user(&state, "newcomer");
+        assert_eq!(call(group_join, json!({"user_id": newcomer, "group_id": group_id}), &state).0, 200);
+        assert_eq!(call(group_join, json!({"user_id": newcomer, "group_id": group_id}), &state), (400, error("user already in group")));
+        assert_eq!(call(group_join, json!({"user_id": newcomer, "group_id": group_id, "upsert": true}), &state).0, 200);
+
+        assert_eq!(call(group_quit, json!({"user_id": newcomer, "group_id": group_id}), &state).0, 200);
+        assert_eq!(access(&state, newcomer, group_id), None);
+        assert_eq!(call(group_quit, json!({"user_id": users[0], "group_id": group_id}), &state),
+            (400, error("user is only one Admin in this group")));
+    }
+
+    #[test]
+    fn private_group_needs_an_invite()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 1);
+        let newcomer = create_user(&state, "newcomer");
+        assert_eq!(call(group_set_private, json!({"admin_id": users[0], "group_id": group_id, "private": true}), &state).0, 200);
+        assert_eq!(call(group_join, json!({"user_id": newcomer, "group_id": group_id}), &state),
+            (400, error("group is private, an invite is required")));
+
+        let (status, body) = call(group_invite, json!({"admin_id": users[0], "group_id": group_id}), &state);
+        assert_eq!(status, 200);
+        let token = body["invite_token"].as_str().unwrap();
+        assert_eq!(call(group_join, json!({"user_id": newcomer, "invite_token": token}), &state).0, 200);
+        assert_eq!(access(&state, newcomer, group_id), Some(false));
+    }
+
+    #[test]
+    fn invite_tokens_are_checked_on_join()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 1);
+        let (first, second) = (create_user(&state, "first"), create_user(&state, "second"));
+        let join = |user_id: Id, token: &str| call(group_join, json!({"user_id": user_id, "invite_token": token}), &state);
+        let no_invite = (404, error("no such invite"));
+        assert_eq!(join(first, "unknown"), no_invite);
+
+        let (_, body) = call(group_invite, json!({"admin_id": users[0], "group_id": group_id}), &state);
+        let single = body["invite_token"].as_str().unwrap().to_string();
+        // Неудачное вступление приглашение не тратит
+        assert_eq!(join(users[0], &single), (400, error("user already in group")));
+        assert_eq!(call(group_join, json!({"user_id": first, "group_id": Id::MAX, "invite_token": single}), &state),
+            (400, error("invite is for another group")));
+        assert_eq!(join(first, &single).0, 200);
+        assert_eq!(join(second, &single), no_invite);
+
+        let (_, body) = call(group_invite, json!({"admin_id": users[0], "group_id": group_id, "ttl_seconds": 60}), &state);
+        let reusable = body["invite_token"].as_str().unwrap().to_string();
+        assert_eq!(join(second, &reusable).0, 200);
+        let third = create_user(&state, "third");
+        assert_eq!(join(third, &reusable).0, 200);
+        // Истекшее приглашение не действует
+        state.write().unwrap().invites.get_mut(&reusable).unwrap().expires_at = Some(unix_time() - 1);
+        assert_eq!(join(create_user(&state, "late"), &reusable), no_invite);
+    }
+
+    #[test]
+    fn unique_names_are_checked_on_join_and_rename()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 2);
+        assert_eq!(call(group_set_unique_names, json!({"admin_id": users[0], "group_id": group_id, "unique_names": true}), &state).0, 200);
+        let namesake = create_user(&state, "USER 1");
+        assert_eq!(call(group_join, json!({"user_id": namesake, "group_id": group_id}), &state),
+            (400, error("name \"user 1\" is already taken in this group")));
+        assert_eq!(call(user_update, json!({"user_id": users[0], "name": "User 1"}), &state),
+            (400, error("name \"user 1\" is already taken in this group")));
+    }
+
+    #[test]
+    fn admin_rights_are_transferred()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 3);
+        assert_eq!(call(group_make_admin, json!({"admin_id": users[0], "group_id": group_id, "member_id": users[1]}), &state).0, 200);
+        assert_eq!(call(group_transfer_admin, json!({"admin_id": users[0], "group_id": group_id, "target_id": users[2]}), &state).0, 200);
+        assert_eq!(access(&state, users[0], group_id), Some(false));
+        assert_eq!(access(&state, users[2], group_id), Some(true));
+        // Отмена make_admin после передачи прав отняла бы права у нового администратора
+        assert_eq!(call(group_undo, json!({"admin_id": users[2], "group_id": group_id}), &state), (400, error("nothing to undo")));
+
+        assert_eq!(call(group_unadmin, json!({"admin_id": users[1], "group_id": group_id}), &state).0, 200);
+        assert_eq!(call(group_unadmin, json!({"admin_id": users[2], "group_id": group_id}), &state), (400, error(
+            "It is impossible to remove the last admin in a group. You can appoint a new admin and repeat or delete the whole group.")));
+    }
+
+    #[test]
+    fn group_settings_are_changed_by_admin_only()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 2);
+        assert_eq!(call(group_set_budget, json!({"admin_id": users[0], "group_id": group_id, "budget": 1500}), &state).0, 200);
+        assert_eq!(call(group_rename, json!({"admin_id": users[0], "group_id": group_id, "name": " Office "}), &state).0, 200);
+        assert_eq!(call(group_rename, json!({"admin_id": users[1], "group_id": group_id, "name": "Mine"}), &state),
+            (400, error("This user is not an admin.")));
+        let group = read_state(&state).groups[&group_id].clone();
+        assert_eq!(group.budget, Some(1500));
+        assert_eq!(group.name, "Office");
+    }
+
+    #[test]
+    fn kick_and_undo()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 3);
+        assert_eq!(call(group_kick, json!({"admin_id": users[0], "group_id": group_id, "target_id": users[1]}), &state).0, 200);
+        assert_eq!(access(&state, users[1], group_id), None);
+        assert_eq!(call(group_undo, json!({"admin_id": users[0], "group_id": group_id}), &state).0, 200);
+        assert_eq!(access(&state, users[1], group_id), Some(false));
+        assert_eq!(call(group_undo, json!({"admin_id": users[0], "group_id": group_id}), &state), (400, error("nothing to undo")));
+    }
+
+    #[test]
+    fn group_merge_moves_members()
+    {
+        let state = new_state();
+        let (source_id, source_users) = group_of(&state, 2);
+        let target_id = create_group(&state, source_users[0], &[]);
+        assert_eq!(call(group_merge, json!({"admin_id": source_users[0], "source_group_id": source_id, "target_group_id": target_id}), &state).0, 200);
+        assert!(!read_state(&state).groups.contains_key(&source_id));
+        assert_eq!(access(&state, source_users[1], target_id), Some(false));
+        assert_eq!(call(group_merge, json!({"admin_id": source_users[0], "source_group_id": target_id, "target_group_id": target_id}), &state),
+            (400, error("cannot merge a group into itself")));
+    }
+
+    #[test]
+    fn group_delete_removes_memberships()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 2);
+        assert_eq!(call(group_delete, json!({"admin_id": users[1], "group_id": group_id}), &state).0, 400);
+        assert_eq!(call(group_delete, json!({"admin_id": users[0], "group_id": group_id}), &state).0, 200);
+        let guard = read_state(&state);
+        assert!(!guard.groups.contains_key(&group_id));
+        assert!(guard.user_groups.keys().all(|key| key.group_id != group_id));
+    }
+
+    #[test]
+    fn draw_respects_exclusions()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 4);
+        assert_eq!(call(group_exclude, json!({"admin_id": users[0], "group_id": group_id, "user_a": users[0], "user_b": users[1]}), &state).0, 200);
+        draw(&state, users[0], group_id);
+        let guard = read_state(&state);
+        let excluded = &guard.exclusions[&group_id];
+        assert_eq!(validate_assignment(group_id, &guard.user_groups, excluded), Ok(()));
+        assert_ne!(guard.user_groups[&UserGroupId{user_id: users[0], group_id}].santa_id, Some(users[1]));
+        assert_ne!(guard.user_groups[&UserGroupId{user_id: users[1], group_id}].santa_id, Some(users[0]));
+    }
+
+    #[test]
+    fn close_draw_and_reopen()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 3);
+        assert_eq!(call(group_secret_santa, json!({"admin_id": users[0], "group_id": group_id}), &state), (400, error("group is not closed")));
+        draw(&state, users[0], group_id);
+        assert_eq!(call(group_secret_santa, json!({"admin_id": users[0], "group_id": group_id}), &state).0, 409);
+
+        let (status, assignments) = call(group_assignments, json!({"admin_id": users[0], "group_id": group_id}), &state);
+        assert_eq!(status, 200);
+        assert_eq!(assignments.as_array().unwrap().len(), 3);
+
+        let (status, body) = call(group_reopen, json!({"admin_id": users[0], "group_id": group_id}), &state);
+        assert_eq!((status, body), (200, json!({"warning": false, "revealed": []})));
+        assert!(!is_drawn(group_id, &read_state(&state).user_groups));
+    }
+
+    #[test]
+    fn reassign_keeps_the_draw_valid()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 4);
+        draw(&state, users[0], group_id);
+        let (status, body) = call(group_reassign, json!({"admin_id": users[0], "group_id": group_id, "leaving_id": users[3]}), &state);
+        assert_eq!(status, 200);
+        assert!(!body["changed"].as_array().unwrap().is_empty());
+        let guard = read_state(&state);
+        assert_eq!(access(&state, users[3], group_id), None);
+        assert_eq!(validate_assignment(group_id, &guard.user_groups, &HashSet::new()), Ok(()));
+    }
+
+    #[test]
+    fn reveal_token_is_issued_after_the_draw()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 2);
+        let body = json!({"admin_id": users[0], "group_id": group_id, "user_id": users[1]});
+        assert_eq!(call(group_reveal_token, body.clone(), &state).0, 400);
+        draw(&state, users[0], group_id);
+        let (status, response) = call(group_reveal_token, body, &state);
+        assert_eq!(status, 200);
+        let token = response["token"].as_str().unwrap();
+        assert!(read_state(&state).reveal_tokens[token] == UserGroupId{user_id: users[1], group_id});
+    }
+
+    #[test]
+    fn group_import_creates_new_ids()
+    {
+        let state = new_state();
+        let snapshot = json!({
+            "group_id": 7,
+            "name": "Office",
+            "closed": false,
+            "budget": 300,
+            "members": [{"id": 1, "name": "Ann", "admin": true}, {"id": 2, "name": "Bob", "admin": false, "wishlist": ["tea"]}],
+            "exclusions": [[1, 2]],
+        });
+        let (status, body) = call(group_import, snapshot, &state);
+        assert_eq!(status, 200);
+        let group_id = body["group_id"].as_u64().unwrap() as Id;
+        let ann = body["users"]["1"].as_u64().unwrap() as Id;
+        let bob = body["users"]["2"].as_u64().unwrap() as Id;
+        let guard = read_state(&state);
+        assert_eq!(guard.groups[&group_id].budget, Some(300));
+        assert_eq!(guard.wishlists[&bob], vec!["tea".to_string()]);
+        assert!(guard.exclusions[&group_id].contains(&exclusion_pair(ann, bob)));
+        drop(guard);
+        assert_eq!(access(&state, ann, group_id), Some(true));
+
+        assert_eq!(call(group_import, json!({"group_id": 7}), &state).0, 422);
+    }
+
+    #[test]
+    fn unknown_group_is_not_found()
+    {
+        let state = new_state();
+        let user_id = create_user(&state, "Ann");
+        let body = json!({"admin_id": user_id, "user_id": user_id, "group_id": Id::MAX, "target_id": user_id,
+            "leaving_id": user_id, "user_a": user_id, "user_b": user_id, "name": "g", "private": true, "unique_names": true});
+        let handlers: [Handler; 17] = [group_join, group_unadmin, group_delete, group_quit, group_set_budget, group_rename,
+            group_invite, group_set_private, group_set_unique_names, group_exclude, group_kick, group_undo, group_close,
+            group_reopen, group_secret_santa, group_assignments, group_reassign];
+        for handler in handlers
+        {
+            assert_eq!(call(handler, body.clone(), &state), (404, error("no such group")));
+        }
+    }
+
+    #[test]
+    fn incomplete_bodies_are_bad_requests()
+    {
+        let state = new_state();
+        let handlers: [(&str, Handler); 27] = [("user_create", user_create), ("users_create_batch", users_create_batch),
+            ("group_create", group_create), ("group_join", group_join), ("group_unadmin", group_unadmin), ("group_delete", group_delete),
+            ("group_merge", group_merge), ("group_make_admin", group_make_admin), ("group_transfer_admin", group_transfer_admin),
+            ("group_quit", group_quit), ("group_set_budget", group_set_budget), ("group_rename", group_rename),
+            ("group_invite", group_invite), ("group_set_private", group_set_private), ("group_set_unique_names", group_set_unique_names),
+            ("group_exclude", group_exclude), ("group_kick", group_kick), ("group_undo", group_undo), ("group_close", group_close),
+            ("group_reopen", group_reopen), ("group_secret_santa", group_secret_santa), ("group_assignments", group_assignments),
+            ("group_reassign", group_reassign), ("group_reveal_token", group_reveal_token), ("user_update", user_update),
+            ("user_wishlist", user_wishlist), ("user_delete", user_delete)];
+        let wrong_types = json!({"name": [], "names": true, "items": true, "user_id": true, "admin_id": true, "creator_id": true,
+            "group_id": true, "source_group_id": true, "member_id": true});
+        for (name, handler) in handlers
+        {
+            let (status, body) = call(handler, json!({}), &state);
+            assert_eq!(status, 400, "{}", name);
+            assert!(body["error"].as_str().unwrap().starts_with("missing field: "), "{}: {}", name, body);
+            // Поле неверного типа - тоже 400, а не паника
+            let (status, body) = call(handler, wrong_types.clone(), &state);
+            assert_eq!(status, 400, "{}", name);
+            assert!(body["error"].as_str().unwrap().starts_with("field "), "{}: {}", name, body);
+        }
+        // Снимок разбирается целиком, поэтому неполный снимок - 422
+        assert_eq!(call(group_import, json!({"name": "Office"}), &state).0, 422);
+        assert!(read_state(&state).users.is_empty());
+    }
+
+    #[test]
+    fn excluded_pairs_are_never_drawn()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 5);
+        for (user_a, user_b) in [(users[0], users[1]), (users[2], users[3]), (users[1], users[4])]
+        {
+            assert_eq!(call(group_exclude, json!({"admin_id": users[0], "group_id": group_id, "user_a": user_a, "user_b": user_b}), &state).0, 200);
+        }
+        assert_eq!(call(group_close, json!({"admin_id": users[0], "group_id": group_id}), &state).0, 200);
+        for seed in 0..200
+        {
+            let (status, body) = call(group_secret_santa, json!({"admin_id": users[0], "group_id": group_id, "seed": seed}), &state);
+            assert_eq!(status, 200, "seed {}: {}", seed, body);
+            let guard = read_state(&state);
+            assert_eq!(validate_assignment(group_id, &guard.user_groups, &guard.exclusions[&group_id]), Ok(()), "seed {}", seed);
+            drop(guard);
+            assert_eq!(call(group_reopen, json!({"admin_id": users[0], "group_id": group_id}), &state).0, 200);
+            assert_eq!(call(group_close, json!({"admin_id": users[0], "group_id": group_id}), &state).0, 200);
+        }
+    }
+
+    #[test]
+    fn impossible_exclusions_are_reported()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 2);
+        assert_eq!(call(group_exclude, json!({"admin_id": users[0], "group_id": group_id, "user_a": users[1], "user_b": users[0]}), &state).0, 200);
+        assert_eq!(call(group_close, json!({"admin_id": users[0], "group_id": group_id}), &state).0, 200);
+        assert_eq!(call(group_secret_santa, json!({"admin_id": users[0], "group_id": group_id}), &state), (400, error("no valid assignment exists")));
+        assert!(!is_drawn(group_id, &read_state(&state).user_groups));
+    }
+
+    // Параллельные запросы к одному состоянию: общая блокировка не дает потерять участника или назначить дважды
+    #[test]
+    fn parallel_joins_and_draw_stay_consistent()
+    {
+        const THREADS: usize = 32;
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 2);
+        let admin_id = users[0];
+        let start = Arc::new(std::sync::Barrier::new(THREADS + 1));
+        let joiners: Vec<_> = (0..THREADS)
+            .map(|index|
+            {
+                let (state, start) = (state.clone(), start.clone());
+                std::thread::spawn(move ||
+                {
+                    start.wait();
+                    let user_id = create_user(&state, &format!("joiner {}", index));
+                    // Каждый второй еще и создает свою группу, чтобы ID групп выдавались параллельно
+                    let own_group_id = (index % 2 == 0).then(|| create_group(&state, user_id, &[]));
+                    let (status, body) = call(group_join, json!({"user_id": user_id, "group_id": group_id}), &state);
+                    assert!(status == 200 || body == error("group is closed"), "{} {}", status, body);
+                    (user_id, own_group_id, status == 200)
+                })
+            })
+            .collect();
+        let drawer =
+        {
+            let (state, start) = (state.clone(), start.clone());
+            std::thread::spawn(move ||
+            {
+                start.wait();
+                std::thread::yield_now();
+                draw(&state, admin_id, group_id);
+            })
+        };
+        let results: Vec<(Id, Option<Id>, bool)> = joiners.into_iter().map(|joiner| joiner.join().unwrap()).collect();
+        drawer.join().unwrap();
+
+        let user_ids: HashSet<Id> = results.iter().map(|(user_id, _, _)| *user_id).collect();
+        assert_eq!(user_ids.len(), THREADS);
+        let group_ids: HashSet<Id> = results.iter().filter_map(|(_, own_group_id, _)| *own_group_id).collect();
+        assert_eq!(group_ids.len(), THREADS / 2);
+        assert!(!group_ids.contains(&group_id));
+
+        // В группе ровно те, чье вступление прошло, и все они участвуют в жеребьевке
+        let mut expected: HashSet<Id> = users.into_iter().collect();
+        expected.extend(results.iter().filter(|(_, _, joined)| *joined).map(|(user_id, _, _)| *user_id));
+        let guard = read_state(&state);
+        assert_eq!(get_member_ids(group_id, &guard.user_groups).collect::<HashSet<Id>>(), expected);
+        assert_eq!(validate_assignment(group_id, &guard.user_groups, &HashSet::new()), Ok(()));
+        assert!(check_invariants(&guard).is_empty());
+    }
+
+    #[test]
+    fn demoted_admin_can_be_promoted_again()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 3);
+        let admins = || count_admins(group_id, &read_state(&state).user_groups);
+        let promote = json!({"admin_id": users[0], "group_id": group_id, "member_id": users[1]});
+        assert_eq!(call(group_make_admin, promote.clone(), &state).0, 200);
+        assert_eq!((access(&state, users[1], group_id), admins()), (Some(true), 2));
+        assert_eq!(call(group_make_admin, promote.clone(), &state), (400, error("user is already an admin")));
+
+        assert_eq!(call(group_unadmin, json!({"admin_id": users[1], "group_id": group_id}), &state).0, 200);
+        assert_eq!((access(&state, users[1], group_id), admins()), (Some(false), 1));
+
+        assert_eq!(call(group_make_admin, promote, &state).0, 200);
+        assert_eq!((access(&state, users[1], group_id), admins()), (Some(true), 2));
+        assert_eq!(access(&state, users[2], group_id), Some(false));
+    }
+
+    #[test]
+    fn departing_members_leave_no_exclusions()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 6);
+        let admin_id = users[0];
+        for (user_a, user_b) in [(users[1], users[2]), (users[3], users[4]), (users[4], users[5])]
+        {
+            assert_eq!(call(group_exclude, json!({"admin_id": admin_id, "group_id": group_id, "user_a": user_a, "user_b": user_b}), &state).0, 200);
+        }
+        let excluded = |state: &Arc<RwLock<DataBase>>| read_state(state).exclusions.get(&group_id).cloned().unwrap_or_default();
+
+        assert_eq!(call(group_kick, json!({"admin_id": admin_id, "group_id": group_id, "target_id": users[2]}), &state).0, 200);
+        assert_eq!(excluded(&state), [exclusion_pair(users[3], users[4]), exclusion_pair(users[4], users[5])].into_iter().collect());
+        assert_eq!(call(user_delete, json!({"user_id": users[3]}), &state).0, 200);
+        assert_eq!(excluded(&state), [exclusion_pair(users[4], users[5])].into_iter().collect());
+
+        draw(&state, admin_id, group_id);
+        assert_eq!(call(group_reassign, json!({"admin_id": admin_id, "group_id": group_id, "leaving_id": users[5]}), &state).0, 200);
+        assert!(!read_state(&state).exclusions.contains_key(&group_id));
+
+        // Без исключений снова можно ограничить длину циклов
+        assert_eq!(call(group_reopen, json!({"admin_id": admin_id, "group_id": group_id}), &state).0, 200);
+        assert_eq!(call(group_close, json!({"admin_id": admin_id, "group_id": group_id}), &state).0, 200);
+        assert_eq!(call(group_secret_santa, json!({"admin_id": admin_id, "group_id": group_id, "max_cycle_length": 3}), &state).0, 200);
+    }
+
+    #[test]
+    fn digest_is_stable_and_follows_the_assignment()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 4);
+        assert_eq!(call(group_close, json!({"admin_id": users[0], "group_id": group_id}), &state).0, 200);
+        let (status, body) = call(group_secret_santa, json!({"admin_id": users[0], "group_id": group_id}), &state);
+        assert_eq!(status, 200);
+        let digest = body["digest"].as_str().unwrap().to_string();
+        assert_eq!(digest.len(), 16);
+        assert!(digest.chars().all(|c| c.is_ascii_hexdigit()));
+
+        let mut guard = state.write().unwrap();
+        assert_eq!(guard.digests[&group_id], digest);
+        assert_eq!(get_assignment_digest(group_id, &guard.user_groups), digest);
+        assert_eq!(get_assignment_digest(group_id, &guard.user_groups), digest);
+        let lines: String = users.iter()
+            .map(|user_id| format!("{}->{}\n", user_id, guard.user_groups[&UserGroupId{user_id: *user_id, group_id}].santa_id.unwrap()))
+            .collect();
+        let expected: String = Sha256::digest(lines.as_bytes()).iter().take(8).map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(digest, expected);
+
+        // Два дарителя меняются получателями
+        let first = UserGroupId{user_id: users[0], group_id};
+        let second = UserGroupId{user_id: users[1], group_id};
+        let santa_first = guard.user_groups[&first].santa_id;
+        let santa_second = guard.user_groups[&second].santa_id;
+        guard.user_groups.get_mut(&first).unwrap().santa_id = santa_second;
+        guard.user_groups.get_mut(&second).unwrap().santa_id = santa_first;
+        assert_ne!(get_assignment_digest(group_id, &guard.user_groups), digest);
+    }
+
+    #[test]
+    fn batch_of_100_gets_consecutive_ids()
+    {
+        let state = new_state();
+        // Одиночные создания параллельно с пакетом не вклиниваются в его диапазон
+        let singles = std::thread::spawn({
+            let state = state.clone();
+            move || (0..50).map(|_| create_user(&state, "single")).collect::<Vec<Id>>()
+        });
+        let names: Vec<String> = (0..100).map(|index| format!("user {}", index)).collect();
+        let (status, body) = call(users_create_batch, json!({"names": names}), &state);
+        let singles = singles.join().unwrap();
+        assert_eq!(status, 200);
+        let users = body.as_array().unwrap();
+        assert_eq!(users.len(), 100);
+        let first = users[0]["id"].as_u64().unwrap() as Id;
+        for (index, user) in users.iter().enumerate()
+        {
+            assert_eq!(user["id"], first + index as Id);
+            assert_eq!(user["name"], names[index]);
+        }
+        let tokens: HashSet<&str> = users.iter().map(|user| user["token"].as_str().unwrap()).collect();
+        assert_eq!(tokens.len(), 100);
+        assert!(singles.iter().all(|id| *id < first || *id >= first + 100));
+        assert_eq!(read_state(&state).users.len(), 150);
+    }
+
+    #[test]
+    fn second_draw_conflicts_and_keeps_the_assignment()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 5);
+        draw(&state, users[0], group_id);
+        let assignments = get_assignments(group_id, &read_state(&state).user_groups);
+        let digest = read_state(&state).digests[&group_id].clone();
+
+        let (status, body) = call(group_secret_santa, json!({"admin_id": users[0], "group_id": group_id, "seed": 2}), &state);
+        assert_eq!(status, 409);
+        assert_eq!(body, json!({"error": "secret santa already started", "assignments": assignments}));
+        assert_eq!(get_assignments(group_id, &read_state(&state).user_groups), assignments);
+        assert_eq!(read_state(&state).digests[&group_id], digest);
+    }
+
+    #[test]
+    fn seed_repeats_the_draw()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 6);
+        let admin_id = users[0];
+        let draw_with = |seed: u64|
+        {
+            assert_eq!(call(group_close, json!({"admin_id": admin_id, "group_id": group_id}), &state).0, 200);
+            let (status, body) = call(group_secret_santa, json!({"admin_id": admin_id, "group_id": group_id, "seed": seed}), &state);
+            assert_eq!((status, &body["seed"]), (200, &json!(seed)));
+            let assignments = get_assignments(group_id, &read_state(&state).user_groups);
+            assert_eq!(call(group_reopen, json!({"admin_id": admin_id, "group_id": group_id}), &state).0, 200);
+            assignments
+        };
+        let first = draw_with(7);
+        assert_eq!(draw_with(7), first);
+        // Случайное совпадение для одного другого seed возможно, для всех - нет
+        assert!((8..16).any(|seed| draw_with(seed) != first));
+    }
+
+    #[test]
+    fn member_removed_from_draw_neither_gives_nor_receives()
+    {
+        let state = new_state();
+        let (group_id, users) = group_of(&state, 4);
+        let (admin_id, out_id) = (users[0], users[2]);
+        let remove = json!({"admin_id": admin_id, "group_id": group_id, "member_id": out_id});
+        assert_eq!(call(group_exclude, json!({"admin_id": admin_id, "group_id": group_id, "user_a": out_id, "user_b": users[3]}), &state).0, 200);
+        assert_eq!(call(group_remove_member_from_draw, remove.clone(), &state), (400, error("group is not closed")));
+
+        assert_eq!(call(group_close, json!({"admin_id": admin_id, "group_id": group_id}), &state).0, 200);
+        assert_eq!(call(group_remove_member_from_draw, json!({"admin_id": users[1], "group_id": group_id, "member_id": out_id}), &state),
+            (400, error("This user is not an admin.")));
+        assert_eq!(call(group_remove_member_from_draw, remove.clone(), &state).0, 200);
+        assert!(!read_state(&state).exclusions.contains_key(&group_id));
+        assert_eq!(call(group_secret_santa, json!({"admin_id": admin_id, "group_id": group_id, "seed": 1}), &state).0, 200);
+        {
+            let guard = read_state(&state);
+            assert_eq!(access(&state, out_id, group_id), Some(false));
+            assert_eq!(guard.user_groups[&UserGroupId{user_id: out_id, group_id}].santa_id, None);
+            assert!(guard.user_groups.values().all(|props| props.santa_id != Some(out_id)));
+            assert_eq!(validate_assignment(group_id, &guard.user_groups, &HashSet::new()), Ok(()));
+        }
+        assert_eq!(call(group_remove_member_from_draw, remove, &state), (400, error("secret santa already started")));
+        assert_eq!(call(group_reveal_token, json!({"admin_id": admin_id, "group_id": group_id, "user_id": out_id}), &state),
+            (400, error("user does not take part in the draw")));
+
+        // Его выход не трогает назначения остальных
+        let digest = read_state(&state).digests[&group_id].clone();
+        let (status, body) = call(group_reassign, json!({"admin_id": admin_id, "group_id": group_id, "leaving_id": out_id}), &state);
+        assert_eq!((status, body), (200, json!({"changed": [], "digest": digest})));
+    }
+
+    #[test]
+    fn user_delete_leaves_open_groups_but_keeps_closed_ones()
+    {
+        let state = new_state();
+        let (closed_id, users) = group_of(&state, 3);
+        let (admin_id, user_id) = (users[0], users[1]);
+        let open_id = create_group(&state, admin_id, &[user_id]);
+        let own_id = create_group(&state, user_id, &[admin_id]);
+        assert_eq!(call(group_close, json!({"admin_id": admin_id, "group_id": closed_id}), &state).0, 200);
+
+        let (status, body) = call(user_delete, json!({"user_id": user_id}), &state);
+        assert_eq!(status, 400);
+        assert_eq!(body["groups"], json!([own_id]));
+        assert!(body["error"].as_str().unwrap().starts_with("User has closed groups. So he was deleted from opened groups, if he wasn't last admin."));
+        assert_eq!(access(&state, user_id, open_id), None);
+        assert_eq!(access(&state, user_id, closed_id), Some(false));
+        assert_eq!(access(&state, user_id, own_id), Some(true));
+        assert!(read_state(&state).users.contains_key(&user_id));
+
+        // Без групп, где он последний администратор, остается только закрытая
+        assert_eq!(call(group_transfer_admin, json!({"admin_id": user_id, "group_id": own_id, "target_id": admin_id}), &state).0, 200);
+        assert_eq!(call(user_delete, json!({"user_id": user_id}), &state),
+            (400, error("User has closed groups. So he was deleted from opened groups.")));
+        assert_eq!(access(&state, user_id, own_id), None);
+        assert_eq!(access(&state, user_id, closed_id), Some(false));
+
+        // После открытия группы пользователь удаляется полностью
+        assert_eq!(call(group_reopen, json!({"admin_id": admin_id, "group_id": closed_id}), &state).0, 200);
+        assert_eq!(call(user_delete, json!({"user_id": user_id}), &state).0, 200);
+        assert_eq!(access(&state, user_id, closed_id), None);
+        assert!(!read_state(&state).users.contains_key(&user_id));
+    }
+}
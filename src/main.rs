@@ -1,9 +1,24 @@
 // # Веб-сервис секретного Санты.
 
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use async_std::io::ReadExt;
 use tide::{Request, Response};
+use tide::http::headers::HeaderValue;
+use tide::listener::{Listener, ToListener};
+use tide::security::CorsMiddleware;
 use serde_json::{Value, json, Map};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+mod handlers;
 
 #[derive(PartialEq,Eq, Clone)]
 enum Access
@@ -14,520 +29,4003 @@ enum Access
 
 type Id = u32;
 
-#[derive(Eq, Hash, PartialEq, Clone, serde::Serialize)]
+#[derive(Eq, Hash, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
 struct UserGroupId
 {
     user_id: Id,
     group_id: Id,
 }
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 struct UserGroupProps
 {
     access_level: Access,
-    santa_id: Id,
+    // None, пока Тайный Кыш Бабай в группе не запущен
+    santa_id: Option<Id>,
+    // Узнавал ли участник, кому дарит подарок
+    revealed: bool,
+    // Снят с жеребьевки закрытой группы, но остается ее участником
+    sits_out: bool,
 }
 impl UserGroupProps {
     fn new(access_level: Access) -> UserGroupProps {
         UserGroupProps {
             access_level,
-            santa_id: 0,
+            santa_id: None,
+            revealed: false,
+            sits_out: false,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, serde::Serialize)]
+struct GroupProps
+{
+    name: String,
+    // Состав группы зафиксирован
+    is_closed: bool,
+    // Имена участников не повторяются без учета регистра
+    unique_names: bool,
+    // Вступить можно только по приглашению из /group/invite
+    is_private: bool,
+    // Наибольшая цена подарка, если администратор ее задал
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budget: Option<u32>,
+}
+impl GroupProps {
+    fn new(name: String) -> GroupProps {
+        GroupProps {
+            name,
+            is_closed: false,
+            unique_names: false,
+            is_private: false,
+            budget: None,
         }
     }
 }
 
+// Приглашение в группу: одноразовое или многоразовое до expires_at (секунды Unix)
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Invite
+{
+    group_id: Id,
+    single_use: bool,
+    expires_at: Option<u64>,
+}
+
+impl Invite {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= unix_time())
+    }
+}
+
+// Имя группы из файла или снимка, сохраненных до появления имен
+fn default_group_name(group_id: Id) -> String
+{
+    format!("group {}", group_id)
+}
+
+// Последнее обратимое изменение состава открытой группы
+enum GroupAction
+{
+    Joined(Id),
+    Quit(Id, Access),
+    Kicked(Id, Access),
+    MadeAdmin(Id),
+    Unadmined(Id),
+}
+
+#[derive(serde::Deserialize)]
+struct UsersQuery
+{
+    q: Option<String>,
+    admin_somewhere: Option<bool>,
+    guests: Option<bool>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct PageQuery
+{
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct UserQuery
+{
+    user_id: Id,
+}
+
+#[derive(serde::Deserialize)]
+struct GroupQuery
+{
+    group_id: Id,
+}
+
+#[derive(serde::Deserialize)]
+struct MembershipQuery
+{
+    user_id: Id,
+    group_id: Id,
+}
+
+#[derive(serde::Deserialize)]
+struct AdminGroupQuery
+{
+    admin_id: Id,
+    group_id: Id,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenQuery
+{
+    token: String,
+}
+
+#[derive(serde::Deserialize)]
+struct GiverQuery
+{
+    admin_id: Id,
+    group_id: Id,
+    receiver_id: Id,
+}
+
+#[derive(serde::Deserialize)]
+struct SnapshotQuery
+{
+    admin_id: Id,
+    group_id: Id,
+    spoil: Option<bool>,
+}
+
+// Все о группе одним документом, для резервной копии или переноса на другой сервер
+#[derive(serde::Serialize, serde::Deserialize)]
+struct GroupSnapshot
+{
+    // Псевдонимы принимают снимок, выгруженный при SANTA_JSON_CASE=camel
+    #[serde(alias = "groupId")]
+    group_id: Id,
+    #[serde(default)]
+    name: String,
+    closed: bool,
+    #[serde(default, alias = "uniqueNames")]
+    unique_names: bool,
+    #[serde(default)]
+    private: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    budget: Option<u32>,
+    members: Vec<MemberSnapshot>,
+    // Пары ID участников из снимка, которые не дарят друг другу
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclusions: Vec<(Id, Id)>,
+    // Хеш назначений закрытой группы; при импорте вычисляется заново
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MemberSnapshot
+{
+    id: Id,
+    name: String,
+    admin: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    wishlist: Vec<String>,
+    // Только при spoil=true
+    #[serde(default, skip_serializing_if = "Option::is_none", alias = "santaId")]
+    santa_id: Option<Id>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not", alias = "sitsOut")]
+    sits_out: bool,
+}
+
 struct DataBase
+{
+    users: HashMap<Id, String>,
+    groups: HashMap<Id, GroupProps>,
+    user_groups: HashMap<UserGroupId, UserGroupProps>,
+    // Одноразовые ссылки на раскрытие: токен -> участник группы
+    reveal_tokens: HashMap<String, UserGroupId>,
+    // Токены пользователей, выданные при создании: токен -> ID пользователя
+    user_tokens: HashMap<String, Id>,
+    // Гостевые пользователи: ID -> момент, после которого гость без групп удаляется
+    guests: HashMap<Id, Instant>,
+    // Хеш назначений, зафиксированный при закрытии группы
+    digests: HashMap<Id, String>,
+    // Что отменит /group/undo в каждой группе
+    last_actions: HashMap<Id, GroupAction>,
+    // Идеи подарков пользователя
+    wishlists: HashMap<Id, Vec<String>>,
+    // Пары участников группы, которые не дарят друг другу, см. exclusion_pair
+    exclusions: HashMap<Id, HashSet<(Id, Id)>>,
+    // Приглашения в группы: токен -> приглашение
+    invites: HashMap<String, Invite>,
+}
+
+impl DataBase {
+    fn new() -> DataBase {
+        DataBase {
+            users: HashMap::new(),
+            groups: HashMap::new(),
+            user_groups: HashMap::new(),
+            reveal_tokens: HashMap::new(),
+            user_tokens: HashMap::new(),
+            guests: HashMap::new(),
+            digests: HashMap::new(),
+            last_actions: HashMap::new(),
+            wishlists: HashMap::new(),
+            exclusions: HashMap::new(),
+            invites: HashMap::new(),
+        }
+    }
+
+    fn save_to_file(&self, path: &str) -> std::io::Result<()> {
+        let stored = StoredDataBase {
+            users: self.users.clone(),
+            users_max_id: USERS_MAX_ID.load(Ordering::Relaxed),
+            groups: self.groups.iter().map(|(id, group)| (*id, group.is_closed)).collect(),
+            group_names: self.groups.iter().map(|(id, group)| (*id, group.name.clone())).collect(),
+            unique_name_groups: self.groups.iter().filter(|(_, group)| group.unique_names).map(|(id, _)| *id).collect(),
+            private_groups: self.groups.iter().filter(|(_, group)| group.is_private).map(|(id, _)| *id).collect(),
+            groups_max_id: GROUPS_MAX_ID.load(Ordering::Relaxed),
+            memberships: self.user_groups.iter()
+                .map(|(key, props)| StoredMembership {
+                    user_id: key.user_id,
+                    group_id: key.group_id,
+                    admin: props.access_level == Access::Admin,
+                    santa_id: props.santa_id,
+                    revealed: props.revealed,
+                    sits_out: props.sits_out,
+                })
+                .collect(),
+            reveal_tokens: self.reveal_tokens.clone(),
+            user_tokens: self.user_tokens.clone(),
+            guests: self.guests.keys().copied().collect(),
+            digests: self.digests.clone(),
+            wishlists: self.wishlists.clone(),
+            budgets: self.groups.iter().filter_map(|(id, group)| group.budget.map(|budget| (*id, budget))).collect(),
+            exclusions: self.exclusions.clone(),
+            invites: self.invites.clone(),
+        };
+        // Запись во временный файл и переименование, чтобы не оставить недописанный файл
+        let temp_path = format!("{}.tmp", path);
+        std::fs::write(&temp_path, serde_json::to_vec(&stored)?)?;
+        std::fs::rename(&temp_path, path)
+    }
+
+    // Восстанавливает и счетчики ID, чтобы новые ID не совпали с сохраненными
+    fn load_from_file(path: &str) -> std::io::Result<DataBase> {
+        let stored: StoredDataBase = serde_json::from_slice(&std::fs::read(path)?)?;
+        USERS_MAX_ID.fetch_max(stored.users_max_id, Ordering::Relaxed);
+        GROUPS_MAX_ID.fetch_max(stored.groups_max_id, Ordering::Relaxed);
+        let mut data = DataBase::new();
+        data.users = stored.users;
+        let mut group_names = stored.group_names;
+        data.groups = stored.groups.into_iter()
+            .map(|(id, is_closed)| (id, GroupProps {
+                name: group_names.remove(&id).unwrap_or_else(|| default_group_name(id)),
+                is_closed,
+                unique_names: stored.unique_name_groups.contains(&id),
+                is_private: stored.private_groups.contains(&id),
+                budget: stored.budgets.get(&id).copied(),
+            }))
+            .collect();
+        for membership in stored.memberships {
+            let mut props = UserGroupProps::new(if membership.admin { Access::Admin } else { Access::User });
+            props.santa_id = membership.santa_id;
+            props.revealed = membership.revealed;
+            props.sits_out = membership.sits_out;
+            data.user_groups.insert(UserGroupId{user_id: membership.user_id, group_id: membership.group_id}, props);
+        }
+        data.reveal_tokens = stored.reveal_tokens;
+        data.user_tokens = stored.user_tokens;
+        // Срок жизни гостей отсчитывается заново
+        let expires = Instant::now() + guest_ttl();
+        data.guests = stored.guests.into_iter().map(|id| (id, expires)).collect();
+        data.digests = stored.digests;
+        data.wishlists = stored.wishlists;
+        data.exclusions = stored.exclusions;
+        data.invites = stored.invites;
+        Ok(data)
+    }
+}
+
+// DataBase в файле SANTA_DATA_FILE. История для /group/undo не сохраняется.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredDataBase
 {
     users: HashMap<Id, String>,
     users_max_id: Id,
     groups: HashMap<Id, bool>,
+    #[serde(default)]
+    group_names: HashMap<Id, String>,
+    #[serde(default)]
+    unique_name_groups: HashSet<Id>,
+    #[serde(default)]
+    private_groups: HashSet<Id>,
     groups_max_id: Id,
-    user_groups: HashMap<UserGroupId, UserGroupProps>,
+    memberships: Vec<StoredMembership>,
+    reveal_tokens: HashMap<String, UserGroupId>,
+    #[serde(default)]
+    user_tokens: HashMap<String, Id>,
+    guests: Vec<Id>,
+    digests: HashMap<Id, String>,
+    #[serde(default)]
+    wishlists: HashMap<Id, Vec<String>>,
+    #[serde(default)]
+    budgets: HashMap<Id, u32>,
+    #[serde(default)]
+    exclusions: HashMap<Id, HashSet<(Id, Id)>>,
+    #[serde(default)]
+    invites: HashMap<String, Invite>,
 }
 
-fn get_field<T>(object: &serde_json::Map<String, Value>, key: &str) -> T
-where
-    T: std::str::FromStr,
-    <T as std::str::FromStr>::Err: std::fmt::Debug,
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredMembership
 {
-    object.get(key).unwrap().as_str().unwrap().parse().unwrap()
+    user_id: Id,
+    group_id: Id,
+    admin: bool,
+    santa_id: Option<Id>,
+    #[serde(default)]
+    revealed: bool,
+    #[serde(default)]
+    sits_out: bool,
 }
 
-fn response_data(value: Value) -> Response
+// Следующие свободные ID. Выдаются без захвата общей блокировки.
+// Только растут, поэтому ID удаленного пользователя или группы не достанется новому.
+static USERS_MAX_ID: AtomicU32 = AtomicU32::new(0);
+static GROUPS_MAX_ID: AtomicU32 = AtomicU32::new(0);
+
+fn new_user_id() -> Id
 {
-    Response::builder(200)
-        .body(tide::Body::from_json(&value).unwrap())
-        .build()
+    USERS_MAX_ID.fetch_add(1, Ordering::Relaxed)
 }
 
-fn response_empty() -> Response
+// count ID подряд
+fn new_user_ids(count: usize) -> std::ops::Range<Id>
 {
-    Response::builder(200).build()
+    let first = USERS_MAX_ID.fetch_add(count as Id, Ordering::Relaxed);
+    first..first + count as Id
 }
 
-fn response_error(msg: &str) -> Response
+fn new_group_id() -> Id
 {
-    Response::builder(400)
-        .body(tide::Body::from_json(&json!({"error": msg})).unwrap())
-        .build()
+    GROUPS_MAX_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// Ожидание блокировки дольше этого порога попадает в лог
+const LOCK_WAIT_WARN: Duration = Duration::from_millis(50);
+
+// Сколько /group/wait_closed ждет жеребьевки в группе, прежде чем ответить 204
+const WAIT_CLOSED_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Срабатывает при каждой жеребьевке
+static GROUP_CLOSED: event_listener::Event = event_listener::Event::new();
+
+// Наибольшая длина имени пользователя или названия группы в символах
+const NAME_MAX_LEN: usize = 100;
+
+// Не больше WISHLIST_MAX_ITEMS идей подарков длиной до WISHLIST_MAX_ITEM_LEN символов
+const WISHLIST_MAX_ITEMS: usize = 20;
+const WISHLIST_MAX_ITEM_LEN: usize = 200;
+
+// Размер страницы /users и /groups: по умолчанию и наибольший
+const PAGE_DEFAULT_LIMIT: usize = 100;
+const PAGE_MAX_LIMIT: usize = 1000;
+
+// Как часто изменения сохраняются в SANTA_DATA_FILE
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Были ли изменения после последнего сохранения
+static STATE_DIRTY: AtomicBool = AtomicBool::new(false);
+
+// Как часто удаляются гости с истекшим сроком
+const GUEST_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Не больше REVEAL_LIMIT запросов раскрытия за REVEAL_WINDOW с одного IP для одной группы
+const REVEAL_LIMIT: u32 = 10;
+const REVEAL_WINDOW: Duration = Duration::from_secs(60);
+
+// (IP, ID группы) -> (начало окна, число запросов в окне)
+type RevealRequests = HashMap<(IpAddr, Id), (Instant, u32)>;
+static REVEAL_REQUESTS: LazyLock<Mutex<RevealRequests>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Общий для всех клиентов лимит запросов (GCRA): момент в микросекундах от SERVER_STARTED,
+// к которому были бы обработаны все принятые запросы при равномерном темпе
+static SERVER_STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+static SERVER_RATE_TAT: AtomicU64 = AtomicU64::new(0);
+
+// Ответы на запросы с заголовком Idempotency-Key хранятся IDEMPOTENCY_KEY_TTL, но не больше
+// IDEMPOTENCY_MAX_KEYS штук: при переполнении вытесняются самые старые
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const IDEMPOTENCY_MAX_KEYS: usize = 10_000;
+
+enum IdempotentResponse
+{
+    // Первый запрос с этим ключом еще обрабатывается
+    InProgress,
+    Done(tide::StatusCode, Option<String>, tide::http::Mime, Vec<u8>),
 }
 
+// "путь ключ" -> (момент первого запроса, отпечаток первого запроса, ответ на него)
+type IdempotentResponses = HashMap<String, (Instant, String, IdempotentResponse)>;
+static IDEMPOTENT_RESPONSES: LazyLock<Mutex<IdempotentResponses>> = LazyLock::new(|| Mutex::new(HashMap::new()));
 
+// Число обработанных запросов для /metrics: (метод, маршрут, код ответа) -> число.
+// Маршрутов не больше METRICS_MAX_ROUTES, остальные учитываются как "other".
+type RequestCounts = HashMap<(String, String, u16), u64>;
+static REQUEST_COUNTS: LazyLock<Mutex<RequestCounts>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+const METRICS_MAX_ROUTES: usize = 200;
 
+static LOCK_ACQUISITIONS: AtomicU64 = AtomicU64::new(0);
+static LOCK_SLOW_WAITS: AtomicU64 = AtomicU64::new(0);
+static LOCK_WAIT_MICROS: AtomicU64 = AtomicU64::new(0);
 
-fn user_create(input_obj: &Map<String, Value>, state: &Arc<Mutex<DataBase>>) -> Response
+// Учет времени ожидания общей блокировки
+fn record_lock_wait(waited: Duration)
 {
-    let name: String = get_field(input_obj, "name");
-    if name.len() > 0
+    let acquisitions = LOCK_ACQUISITIONS.fetch_add(1, Ordering::Relaxed) + 1;
+    let total_micros = LOCK_WAIT_MICROS.fetch_add(waited.as_micros() as u64, Ordering::Relaxed) + waited.as_micros() as u64;
+    if waited > LOCK_WAIT_WARN
     {
-        let mut guard = state.lock().unwrap();
-        let id = guard.users_max_id;
-        guard.users.insert(id, name);
-        guard.users_max_id += 1;
+        let slow_waits = LOCK_SLOW_WAITS.fetch_add(1, Ordering::Relaxed) + 1;
+        eprintln!(
+            "state lock waited {} ms ({} slow waits of {} acquisitions, {} ms waited in total)",
+            waited.as_millis(), slow_waits, acquisitions, total_micros / 1000
+        );
+    }
+}
+
+// Захват общей блокировки на чтение; читатели не мешают друг другу.
+// Если обработчик запаниковал с захваченной блокировкой, данные берутся как есть,
+// иначе все следующие запросы тоже паниковали бы.
+fn read_state(state: &Arc<RwLock<DataBase>>) -> RwLockReadGuard<'_, DataBase>
+{
+    let started = Instant::now();
+    let guard = state.read().unwrap_or_else(PoisonError::into_inner);
+    record_lock_wait(started.elapsed());
+    guard
+}
+
+// Захват общей блокировки на запись; при панике обработчика - как в read_state
+fn write_state(state: &Arc<RwLock<DataBase>>) -> RwLockWriteGuard<'_, DataBase>
+{
+    let started = Instant::now();
+    let guard = state.write().unwrap_or_else(PoisonError::into_inner);
+    record_lock_wait(started.elapsed());
+    STATE_DIRTY.store(true, Ordering::Relaxed);
+    guard
+}
 
-        response_data(json!({"id": id}))
+// Учитывает запрос раскрытия и проверяет, не превышен ли лимит для IP и группы.
+// None, если запрос разрешен, иначе через сколько секунд закончится окно.
+fn reveal_retry_after(peer_addr: Option<&str>, group_id: Id) -> Option<u64>
+{
+    let ip = peer_addr.and_then(|addr| addr.parse::<SocketAddr>().ok())?.ip();
+    let now = Instant::now();
+    let mut requests = REVEAL_REQUESTS.lock().unwrap_or_else(PoisonError::into_inner);
+    requests.retain(|_, (started, _)| now.duration_since(*started) < REVEAL_WINDOW);
+    let (started, count) = requests.entry((ip, group_id)).or_insert((now, 0));
+    *count += 1;
+    if *count <= REVEAL_LIMIT
+    {
+        return None;
     }
-    else
+    let remaining = REVEAL_WINDOW.saturating_sub(now.duration_since(*started));
+    Some(remaining.as_micros().div_ceil(1_000_000).max(1) as u64)
+}
+
+// Ошибки 400 для отсутствующего поля входных данных и для поля не того типа.
+// Тело ответа с полем `error` добавляет error_body.
+fn missing_field(key: &str) -> tide::Error
+{
+    tide::Error::from_str(400, format!("missing field: {}", key))
+}
+
+fn wrong_field_type(key: &str, expected: &str) -> tide::Error
+{
+    tide::Error::from_str(400, format!("field {} must be {}", key, expected))
+}
+
+fn get_object(body: &Value) -> tide::Result<&Map<String, Value>>
+{
+    body.as_object().ok_or_else(|| tide::Error::from_str(400, "request body is not a JSON object"))
+}
+
+// Значение поля key, из которого разбирается T. Строковое поле должно быть строкой,
+// числовое - числом или строкой с числом.
+fn parse_value<T: std::str::FromStr>(value: &Value, key: &str) -> tide::Result<T>
+{
+    let is_text = std::any::type_name::<T>() == std::any::type_name::<String>();
+    let parsed = match value
+    {
+        Value::String(text) => text.parse().ok(),
+        Value::Number(number) if !is_text => number.to_string().parse().ok(),
+        _ => None,
+    };
+    parsed.ok_or_else(|| wrong_field_type(key, if is_text { "a string" } else { "a number" }))
+}
+
+fn get_field<T: std::str::FromStr>(object: &serde_json::Map<String, Value>, key: &str) -> tide::Result<T>
+{
+    object.get(key).ok_or_else(|| missing_field(key)).and_then(|value| parse_value(value, key))
+}
+
+fn get_bool_field(object: &serde_json::Map<String, Value>, key: &str) -> tide::Result<bool>
+{
+    object.get(key).ok_or_else(|| missing_field(key))?.as_bool().ok_or_else(|| wrong_field_type(key, "true or false"))
+}
+
+// Необязательный флаг: false, если поля нет или оно null, и ошибка, если это не true или false
+fn get_optional_bool_field(object: &serde_json::Map<String, Value>, key: &str) -> tide::Result<bool>
+{
+    match object.get(key).filter(|value| !value.is_null())
     {
-        response_error("bad name")
+        None => Ok(false),
+        Some(value) => value.as_bool().ok_or_else(|| wrong_field_type(key, "true or false")),
     }
 }
 
-fn does_user_belong_to_group(user_id: Id, group_id: Id, user_groups: &HashMap<UserGroupId,UserGroupProps>) -> bool
+fn get_optional_field<T: std::str::FromStr>(object: &serde_json::Map<String, Value>, key: &str) -> tide::Result<Option<T>>
 {
-    return user_groups.contains_key(&UserGroupId { user_id, group_id });
+    object.get(key).filter(|value| !value.is_null()).map(|value| parse_value(value, key)).transpose()
 }
 
-fn count_admins(group_id: Id, user_groups: &HashMap<UserGroupId, UserGroupProps>) ->usize
+// Наибольшая длина массива во входных данных, задается переменной окружения SANTA_MAX_ARRAY_LEN
+fn max_array_len() -> usize
 {
-    let iter = user_groups.into_iter();
-    let collection = iter.filter(|&x| x.0.group_id == group_id && x.1.access_level == Access::Admin);
-    return collection.count();
+    std::env::var("SANTA_MAX_ARRAY_LEN").ok().and_then(|value| value.parse().ok()).unwrap_or(1000)
 }
-fn is_admin(user_id: Id, group_id: Id, map: &HashMap<UserGroupId, UserGroupProps>) -> bool
+
+// Путь к первому массиву внутри value длиннее max_len, на любой глубине: `members`,
+// `members[0].wishlist`. Пустой путь - сам value.
+fn find_long_array(value: &Value, path: &str, max_len: usize) -> Option<String>
 {
-    map.get(
-        &UserGroupId {
-            user_id,
-            group_id,
+    match value
+    {
+        Value::Array(array) if array.len() > max_len => Some(path.to_string()),
+        Value::Array(array) => array.iter().enumerate()
+            .find_map(|(index, item)| find_long_array(item, &format!("{}[{}]", path, index), max_len)),
+        Value::Object(object) => object.iter()
+            .find_map(|(key, item)| find_long_array(item, &if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) }, max_len)),
+        _ => None,
+    }
+}
+
+// Ни один массив в JSON теле запроса не может быть длиннее max_array_len().
+// Тело, которое не разбирается как JSON, пропускается: его отклонит обработчик.
+fn limit_array_len<'a>(mut request: Request<Arc<RwLock<DataBase>>>, next: tide::Next<'a, Arc<RwLock<DataBase>>>)
+    -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>>
+{
+    Box::pin(async move {
+        // Тело уже ограничено limit_body_size, его можно прочитать целиком и вернуть в запрос
+        let body = request.body_bytes().await?;
+        let max_len = max_array_len();
+        let long_array = serde_json::from_slice::<Value>(&body).ok()
+            .and_then(|value| find_long_array(&value, "", max_len));
+        request.set_body(body);
+        Ok(match long_array
+        {
+            Some(path) => response_error(&format!("field `{}` has more than {} items", path, max_len)),
+            None => next.run(request).await,
+        })
+    })
+}
+
+// Необязательный список строк, каждая из которых разбирается так же, как в get_field
+fn get_list_field<T: std::str::FromStr>(object: &serde_json::Map<String, Value>, key: &str) -> tide::Result<Vec<T>>
+{
+    match object.get(key)
+    {
+        None => Ok(Vec::new()),
+        Some(value) => value.as_array().ok_or_else(|| wrong_field_type(key, "an array"))?.iter().map(|item| parse_value(item, key)).collect(),
+    }
+}
+
+// Наименьшее число участников, с которым группу можно закрыть и провести жеребьевку:
+// SANTA_MIN_GROUP_SIZE. По умолчанию и не меньше 2.
+fn min_group_size() -> usize
+{
+    std::env::var("SANTA_MIN_GROUP_SIZE").ok().and_then(|value| value.parse().ok()).unwrap_or(2).max(2)
+}
+
+// Адрес, на котором сервер принимает запросы: SANTA_BIND_ADDR. По умолчанию 127.0.0.1:8080.
+fn bind_addr() -> Result<SocketAddr, std::io::Error>
+{
+    let value = std::env::var("SANTA_BIND_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    value.parse().map_err(|_| std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("SANTA_BIND_ADDR must be an IP address with a port, like 0.0.0.0:8080, got {:?}", value),
+    ))
+}
+
+// Файл, в котором хранится состояние сервера: SANTA_DATA_FILE. Без него все хранится только в памяти.
+fn data_file() -> Option<String>
+{
+    std::env::var("SANTA_DATA_FILE").ok().filter(|path| !path.is_empty())
+}
+
+// Срок жизни гостя, не вступившего ни в одну группу, в секундах: SANTA_GUEST_TTL. По умолчанию час.
+fn guest_ttl() -> Duration
+{
+    Duration::from_secs(std::env::var("SANTA_GUEST_TTL").ok().and_then(|value| value.parse().ok()).unwrap_or(3600))
+}
+
+// Наибольшее число запросов в секунду ко всему серверу: SANTA_MAX_RPS. По умолчанию 1000.
+// Допускается всплеск до того же числа запросов сразу.
+fn max_rps() -> u64
+{
+    std::env::var("SANTA_MAX_RPS").ok().and_then(|value| value.parse().ok()).filter(|rps| *rps > 0).unwrap_or(1000)
+}
+
+// None, если запрос укладывается в лимит сервера, иначе через сколько секунд повторить
+fn server_rate_retry_after() -> Option<u64>
+{
+    let rps = max_rps();
+    let interval = 1_000_000 / rps;
+    let burst = interval * rps;
+    let now = SERVER_STARTED.elapsed().as_micros() as u64;
+    let mut tat = SERVER_RATE_TAT.load(Ordering::Relaxed);
+    loop
+    {
+        let new_tat = tat.max(now) + interval;
+        if new_tat - now > burst
+        {
+            return Some((new_tat - now - burst).div_ceil(1_000_000));
         }
-    ).unwrap().access_level == Access::Admin
+        match SERVER_RATE_TAT.compare_exchange_weak(tat, new_tat, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => return None,
+            Err(current) => tat = current,
+        }
+    }
 }
 
-fn get_secret_santas(group: &Vec<Id>) -> Vec<Id>
+fn limit_server_rate<'a>(request: Request<Arc<RwLock<DataBase>>>, next: tide::Next<'a, Arc<RwLock<DataBase>>>)
+    -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>>
 {
-    //Пользователю присваивается santa_id = Id предыдущего в group
-    //Первому присваивается последний
-    let mut result = Vec::with_capacity(group.len());
-    result.push(0);
-    for i in 0..(group.len() - 1)
+    Box::pin(async move {
+        Ok(match server_rate_retry_after()
+        {
+            None => next.run(request).await,
+            Some(retry_after) => Response::builder(503)
+                .header("Retry-After", retry_after.to_string())
+                .body(json_body(json!({"error": "server is overloaded, try again later"})))
+                .build(),
+        })
+    })
+}
+
+// Источники, которым разрешено обращаться к API из браузера: SANTA_CORS_ORIGINS, через запятую, или `*` - любым.
+// Без этой переменной заголовки CORS не выдаются.
+fn cors_origins() -> Option<Vec<String>>
+{
+    std::env::var("SANTA_CORS_ORIGINS").ok()
+        .map(|value| value.split(',').map(|origin| origin.trim().to_string()).filter(|origin| !origin.is_empty()).collect::<Vec<String>>())
+        .filter(|origins| !origins.is_empty())
+}
+
+// Ключ API: SANTA_API_KEY. Если задан, изменяющие запросы должны передавать заголовок
+// `Authorization: Bearer <ключ>`; при SANTA_API_KEY_READS=true - и запросы GET.
+fn api_key() -> Option<String>
+{
+    std::env::var("SANTA_API_KEY").ok().filter(|key| !key.is_empty())
+}
+
+fn api_key_reads() -> bool
+{
+    std::env::var("SANTA_API_KEY_READS").is_ok_and(|value| value == "true")
+}
+
+// Пути, открытые без ключа даже при SANTA_API_KEY_READS: проверки доступности и
+// раскрытие по одноразовой ссылке, у которой свой токен
+const API_KEY_EXEMPT_PATHS: &[&str] = &["/health", "/healthz", "/reveal"];
+
+fn require_api_key<'a>(request: Request<Arc<RwLock<DataBase>>>, next: tide::Next<'a, Arc<RwLock<DataBase>>>)
+    -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>>
+{
+    Box::pin(async move {
+        let key = match api_key()
+        {
+            None => return Ok(next.run(request).await),
+            Some(key) => key,
+        };
+        let is_read = request.method() == tide::http::Method::Get;
+        let exempt = (is_read && !api_key_reads()) || API_KEY_EXEMPT_PATHS.contains(&request.url().path());
+        let authorized = request.header("Authorization")
+            .and_then(|values| values.last().as_str().strip_prefix("Bearer "))
+            .is_some_and(|given| given == key);
+        Ok(if exempt || authorized
+        {
+            next.run(request).await
+        }
+        else
+        {
+            Response::builder(401)
+                .header("WWW-Authenticate", "Bearer")
+                .body(json_body(json!({"error": "missing or wrong API key"})))
+                .build()
+        })
+    })
+}
+
+// Проверять ли, что запрос делает сам пользователь: SANTA_USER_AUTH=true. Тогда запрос, в котором
+// есть ID действующего пользователя, должен передавать его токен в заголовке X-User-Token.
+fn user_auth() -> bool
+{
+    std::env::var("SANTA_USER_AUTH").is_ok_and(|value| value == "true")
+}
+
+// Поля с ID того, кто делает запрос. В /group/reveal_token user_id - участник, которому
+// администратор выдает ссылку.
+fn acting_user_fields(path: &str) -> &'static [&'static str]
+{
+    if path == "/group/reveal_token"
     {
-        result.push(group[i]);
+        &["admin_id"]
+    }
+    else
+    {
+        &["user_id", "admin_id", "creator_id"]
     }
-    result[0] = group[group.len() - 1];
-    result
 }
 
-fn main() -> Result<(), std::io::Error> 
+// ID действующего пользователя из параметров пути, строки запроса и JSON тела.
+// Значения, которые не разбираются как ID, пропускаются: их отклонит обработчик.
+fn acting_user_ids(request: &Request<Arc<RwLock<DataBase>>>, body: &[u8]) -> Vec<Id>
 {
-    let f = async {
-        let data = DataBase
+    let fields = acting_user_fields(request.url().path());
+    let mut ids: Vec<Id> = fields.iter()
+        .filter_map(|field| request.param(field).ok())
+        .filter_map(|value| value.parse().ok())
+        .collect();
+    ids.extend(request.url().query_pairs()
+        .filter(|(key, _)| fields.contains(&key.as_ref()))
+        .filter_map(|(_, value)| value.parse::<Id>().ok()));
+    if let Ok(Value::Object(object)) = serde_json::from_slice::<Value>(body)
+    {
+        ids.extend(fields.iter()
+            .filter_map(|field| object.get(*field).and_then(|value| parse_value::<Id>(value, field).ok())));
+    }
+    ids
+}
+
+fn check_acting_user<'a>(mut request: Request<Arc<RwLock<DataBase>>>, next: tide::Next<'a, Arc<RwLock<DataBase>>>)
+    -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>>
+{
+    Box::pin(async move {
+        if !user_auth()
         {
-            users: HashMap::new(),
-            users_max_id: 0,
-            groups: HashMap::new(),
-            groups_max_id: 0,
-            user_groups: HashMap::new(),
+            return Ok(next.run(request).await);
+        }
+        let body = request.body_bytes().await?;
+        let ids = acting_user_ids(&request, &body);
+        request.set_body(body);
+        if ids.is_empty()
+        {
+            return Ok(next.run(request).await);
+        }
+        let authenticated = request.header("X-User-Token")
+            .and_then(|values| read_state(request.state()).user_tokens.get(values.last().as_str()).copied());
+        Ok(match authenticated
+        {
+            None => Response::builder(401)
+                .body(json_body(json!({"error": "missing or unknown user token"})))
+                .build(),
+            Some(user_id) if ids.iter().any(|id| *id != user_id) =>
+                Response::builder(403)
+                    .body(json_body(json!({"error": "cannot act as another user"})))
+                    .build(),
+            Some(_) => next.run(request).await,
+        })
+    })
+}
+
+// Наибольший размер тела запроса в байтах: SANTA_MAX_BODY_BYTES. По умолчанию 1 МиБ.
+fn max_body_bytes() -> usize
+{
+    std::env::var("SANTA_MAX_BODY_BYTES").ok().and_then(|value| value.parse().ok()).unwrap_or(1 << 20)
+}
+
+// Тело без Content-Length читается не дальше лимита, чтобы не держать в памяти все присланное
+fn limit_body_size<'a>(mut request: Request<Arc<RwLock<DataBase>>>, next: tide::Next<'a, Arc<RwLock<DataBase>>>)
+    -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>>
+{
+    Box::pin(async move {
+        let max_bytes = max_body_bytes();
+        let too_large = match request.len()
+        {
+            Some(len) => len > max_bytes,
+            None =>
+            {
+                let mut body = Vec::new();
+                request.take_body().take(max_bytes as u64 + 1).read_to_end(&mut body).await?;
+                let too_large = body.len() > max_bytes;
+                request.set_body(body);
+                too_large
+            }
         };
-        let state = Arc::new(Mutex::new(data));
-        let mut app = tide::with_state(state);
-
-        // Routes
-        app.at("/users")
-            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
-                let guard = request.state().lock().unwrap();
-                Ok(json!(guard.users))
-            });
-        app.at("/groups")
-            .get(|request: Request<Arc<Mutex<DataBase>>>| async move {
-                let guard = request.state().lock().unwrap();
-                Ok(json!(guard.groups))
-            });
-        
-        app.at("/user/create")
-            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let body: Value = request.body_json().await?;
-                let input_obj = body.as_object().unwrap();
-                Ok(user_create(input_obj, request.state()))
-            });
-        app.at("/group/create")
-            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let body: Value = request.body_json().await?;
-                let object = body.as_object().unwrap();
-                let creator_id: Id = get_field(object, "creator_id");
-
-                let mut guard = request.state().lock().unwrap();
-                Ok(if !guard.users.contains_key(&creator_id)
-                {
-                    response_error("no such user")
-                }
-                else
-                {
-                    let id = guard.groups_max_id;
-                    guard.groups.insert(id, false);
-                    guard.groups_max_id += 1;
-                    guard.user_groups.insert(
-                        UserGroupId
-                        {
-                            user_id: creator_id,
-                            group_id: id,
-                        },
-                        UserGroupProps::new(Access::Admin)
-                    );
-                    response_data(json!({"group_id": id}))
-                })
-            });
-        app.at("/group/join")
-            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let value: Value = request.body_json().await.unwrap();
-                let object = value.as_object().unwrap();
-                let user_id = get_field(object, "user_id");
-                let group_id = get_field(object, "group_id");
-
-                let mut guard = request.state().lock().unwrap();
-                Ok(match guard.groups.get(&group_id)
-                {
-                    None => response_error("no such group"),
-                    Some(is_closed) =>
-                    {
-                        if *is_closed
-                        {
-                            response_error("group is closed")
-                        }
-                        else
-                        {
-                            if !guard.users.contains_key(&user_id)
-                            {
-                                response_error("no such user")
-                            }
-                            else
-                            {
-                                let user_group_id = UserGroupId{user_id, group_id};
-                                if guard.user_groups.contains_key(&user_group_id)
-                                {
-                                    response_error("user already in group")
-                                }
-                                else
-                                {
-                                    guard.user_groups.insert(user_group_id, UserGroupProps::new(Access::User));
-                                    response_empty()
-                                }
-                            }
-                        }
-                    },
-                })
-            });
-        app.at("/group/unadmin")
-            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let body: Value = request.body_json().await?;
-                let object = body.as_object().unwrap();
-                let admin_id = get_field(object, "admin_id");
-                let group_id = get_field(object, "group_id");
-
-                let mut guard = request.state().lock().unwrap();
-                let user_group_id = UserGroupId{user_id: admin_id, group_id};
-                Ok(match guard.user_groups.get(&user_group_id)
-                {
-                    None => response_error("user does not belong to this group"),
-                    Some(user_group_props) =>
-                    {
-                        if user_group_props.access_level != Access::Admin
-                        {
-                            response_error("This user is not an admin.")
-                        }
-                        else
-                        {
-                            if count_admins(group_id, &guard.user_groups) < 2
-                            {
-                                response_error("It is impossible to remove the last admin in a group. You can appoint a new admin and repeat or delete the whole group.")
-                            }
-                            else
-                            {
-                                guard.user_groups.get_mut(&user_group_id).unwrap().access_level = Access::User;
-                                response_empty()
-                            }
-                        }
-                    }
-                })
-            });
-        app.at("/group/delete")
-            .delete(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let body: Value = request.body_json().await?;
-                let object = body.as_object().unwrap();
-                let admin_id = get_field(object, "admin_id");
-                let group_id = get_field(object, "group_id");
-
-                let mut guard = request.state().lock().unwrap();
-                Ok(match guard.user_groups.get(&UserGroupId{user_id: admin_id, group_id: group_id})
+        Ok(if too_large
+        {
+            response_payload_too_large(&format!("request body is larger than {} bytes", max_bytes))
+        }
+        else
+        {
+            next.run(request).await
+        })
+    })
+}
+
+// Какие запросы выводятся в stderr: SANTA_LOG_LEVEL=off - никакие, error - завершившиеся ошибкой
+// (по умолчанию), info - все
+#[derive(PartialEq, PartialOrd)]
+enum LogLevel
+{
+    Off,
+    Error,
+    Info,
+}
+
+fn log_level() -> LogLevel
+{
+    match std::env::var("SANTA_LOG_LEVEL").as_deref()
+    {
+        Ok("off") => LogLevel::Off,
+        Ok("info") => LogLevel::Info,
+        _ => LogLevel::Error,
+    }
+}
+
+// Поля, значения которых не попадают в журнал
+const SENSITIVE_FIELDS: &[&str] = &["token", "invite_token"];
+
+// Поля запроса для журнала: параметры строки запроса и поля JSON тела.
+// Вместо массивов и объектов выводится только их размер, чтобы снимок группы не занял весь журнал.
+fn log_fields(request: &Request<Arc<RwLock<DataBase>>>, body: &[u8]) -> Map<String, Value>
+{
+    let mut fields: Map<String, Value> = request.url().query_pairs()
+        .map(|(key, value)| (key.into_owned(), Value::String(value.into_owned())))
+        .collect();
+    if let Ok(Value::Object(object)) = serde_json::from_slice(body)
+    {
+        for (key, value) in object
+        {
+            let value = match value
+            {
+                Value::Array(array) => json!(format!("<{} items>", array.len())),
+                Value::Object(object) => json!(format!("<{} fields>", object.len())),
+                value => value,
+            };
+            fields.insert(key, value);
+        }
+    }
+    for (key, value) in fields.iter_mut()
+    {
+        if SENSITIVE_FIELDS.contains(&key.as_str())
+        {
+            *value = json!("***");
+        }
+    }
+    fields
+}
+
+// Одна строка на запрос: метод, путь, поля запроса, код ответа и время обработки
+fn log_requests<'a>(mut request: Request<Arc<RwLock<DataBase>>>, next: tide::Next<'a, Arc<RwLock<DataBase>>>)
+    -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>>
+{
+    Box::pin(async move {
+        let level = log_level();
+        if level == LogLevel::Off
+        {
+            return Ok(next.run(request).await);
+        }
+        // Тело уже ограничено limit_body_size, его можно прочитать целиком и вернуть в запрос
+        let body = request.body_bytes().await?;
+        let line = format!("{} {} {}", request.method(), request.url().path(), Value::Object(log_fields(&request, &body)));
+        request.set_body(body);
+        let started = Instant::now();
+        let response = next.run(request).await;
+        let status = response.status();
+        if level >= LogLevel::Info || status.is_client_error() || status.is_server_error()
+        {
+            eprintln!("{} -> {} in {} ms", line, status as u16, started.elapsed().as_millis());
+        }
+        Ok(response)
+    })
+}
+
+// Ошибка, возвращенная обработчиком через `?`, получает тело {"error": ...}, как у response_error
+fn error_body<'a>(request: Request<Arc<RwLock<DataBase>>>, next: tide::Next<'a, Arc<RwLock<DataBase>>>)
+    -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>>
+{
+    Box::pin(async move {
+        let mut response = next.run(request).await;
+        if let Some(msg) = response.error().map(|error| error.to_string())
+        {
+            if response.is_empty() != Some(false)
+            {
+                response.set_body(json_body(json!({"error": msg})));
+            }
+        }
+        Ok(response)
+    })
+}
+
+// Пути, на которых повтор запроса с тем же Idempotency-Key не создает второй объект
+const IDEMPOTENT_PATHS: &[&str] = &["/user/create", "/users/create_batch", "/group/create"];
+
+fn lock_idempotent_responses() -> std::sync::MutexGuard<'static, IdempotentResponses>
+{
+    IDEMPOTENT_RESPONSES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Отпечаток запроса с Idempotency-Key: SHA-256 от того, кто его отправил (X-User-Token или
+// Authorization), и тела. Ключ, повторенный с другим телом или другим отправителем, - ошибка клиента,
+// а не повтор, и сохраненный ответ другого запроса ему не отдается.
+fn request_fingerprint(request: &Request<Arc<RwLock<DataBase>>>, body: &[u8]) -> String
+{
+    let mut hasher = Sha256::new();
+    for name in ["X-User-Token", "Authorization"]
+    {
+        let value = request.header(name).map(|values| values.last().as_str()).unwrap_or("");
+        hasher.update(value.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.update(body);
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Повтор запроса с уже встречавшимся ключом получает сохраненный ответ без повторного выполнения.
+// Сохраняются только успешные (2xx) ответы: после ошибки запрос с тем же ключом выполняется заново.
+fn replay_idempotent<'a>(mut request: Request<Arc<RwLock<DataBase>>>, next: tide::Next<'a, Arc<RwLock<DataBase>>>)
+    -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>>
+{
+    Box::pin(async move {
+        let path = request.url().path();
+        let key = match request.header("Idempotency-Key")
+        {
+            Some(values) if request.method() == tide::http::Method::Post && IDEMPOTENT_PATHS.contains(&path) =>
+                format!("{} {}", path, values.last().as_str()),
+            _ => return Ok(next.run(request).await),
+        };
+        // Тело уже ограничено limit_body_size
+        let body = request.body_bytes().await?;
+        let fingerprint = request_fingerprint(&request, &body);
+        request.set_body(body);
+
+        {
+            let now = Instant::now();
+            let mut responses = lock_idempotent_responses();
+            responses.retain(|_, (created, _, _)| now.duration_since(*created) < IDEMPOTENCY_KEY_TTL);
+            match responses.get(&key)
+            {
+                Some((_, stored_fingerprint, _)) if *stored_fingerprint != fingerprint => return Ok(Response::builder(422)
+                    .body(json_body(json!({"error": "Idempotency-Key was already used for a different request"})))
+                    .build()),
+                Some((_, _, IdempotentResponse::InProgress)) => return Ok(Response::builder(409)
+                    .body(json_body(json!({"error": "a request with this Idempotency-Key is in progress"})))
+                    .build()),
+                Some((_, _, IdempotentResponse::Done(status, location, mime, body))) =>
                 {
-                    None => response_error("user does not belong to this group"),
-                    Some(user_group_props) =>
+                    let mut response = Response::builder(*status)
+                        .header("Idempotent-Replayed", "true")
+                        .body(body.clone())
+                        .content_type(mime.clone())
+                        .build();
+                    if let Some(location) = location
                     {
-                        if user_group_props.access_level != Access::Admin
-                        {
-                            response_error("This user is not an admin.")
-                        }
-                        else
-                        {
-                            // Before delete group, we need to delete all users from this group
-                            guard.user_groups.retain(|user_group_id, _|
-                                {
-                                    user_group_id.group_id != group_id
-                                });
-                            guard.groups.remove(&group_id);
-                            response_empty()
-                        }
+                        response.insert_header("Location", location.as_str());
                     }
+                    return Ok(response);
                 }
-            )});
-        app.at("/group/make_admin")
-            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let body: Value = request.body_json().await?;
-                let object = body.as_object().unwrap();
-                let group_id: Id = get_field(object, "group_id");
-                let member_id: Id = get_field(object, "member_id");
-                let admin_id: Id = get_field(object, "admin_id");
-
-                let mut guard = request.state().lock().unwrap();
-                Ok(if !guard.groups.contains_key(&group_id)
-                {
-                    response_error("no such group")
-                }
-                else if !does_user_belong_to_group(member_id, group_id, &guard.user_groups)
-                {
-                    response_error("user isn't a member of the group")
-                }
-                else if is_admin(member_id, group_id, &guard.user_groups)
-                {
-                    response_error("user is already an admin")
-                }
-                else if !is_admin(admin_id, group_id, &guard.user_groups)
+                None => (),
+            }
+            if responses.len() >= IDEMPOTENCY_MAX_KEYS
+            {
+                let oldest = responses.iter().min_by_key(|(_, (created, _, _))| *created).map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest
                 {
-                    response_error("admin_id isn't an actual admin's ID")
-                }
-                else {
-                    guard.user_groups.insert(
-                        UserGroupId {
-                            user_id: member_id,
-                            group_id,
-                        },
-                        UserGroupProps::new(Access::Admin),
-                    );
-                    response_empty()
-                }
-            )});
-        app.at("/group/quit")
-            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let body: Value = request.body_json().await?;
-                let object = body.as_object().unwrap();
-                let group_id: Id = get_field(object, "group_id");
-                let user_id: Id = get_field(object, "user_id");
-
-                let mut guard = request.state().lock().unwrap();
-                let user_group_id = UserGroupId{user_id, group_id};
-                Ok(match guard.user_groups.get(&user_group_id)
-                {
-                    None => response_error("user does not belong to this group"),
-                    Some(user_group_props) =>
-                    {
-                        if user_group_props.access_level == Access::Admin && count_admins(group_id, &guard.user_groups) < 2
-                        {
-                            response_error("user is only one Admin in this group")
-                        }
-                        else
-                        {
-                            if *guard.groups.get(&group_id).unwrap()
-                            {
-                                response_error("group is closed")
-                            }
-                            else
-                            {
-                                guard.user_groups.remove(&user_group_id);
-                                response_empty()
-                            }
-                        }
-                    }
-                })
-            });
-        app.at("/group/target_by_id/:user_id/:group_id")
-            .get(|request: Request<Arc<Mutex<DataBase>>>| async move{
-                let first_id = request.param("user_id")?;
-                let second_id = request.param("group_id")?;
-                for c in first_id.chars() {
-                    if !c.is_numeric() {
-                        return Ok(response_error("Wrong format user id"));
-                    } 
+                    responses.remove(&oldest);
                 }
-                for c in second_id.chars() {
-                    if !c.is_numeric() {
-                        return Ok(response_error("Wrong format group id"));
-                    } 
-                }
-                let user_id: Id = first_id.parse().unwrap(); // TODO
-                let group_id: Id = second_id.parse().unwrap();
+            }
+            responses.insert(key.clone(), (now, fingerprint, IdempotentResponse::InProgress));
+        }
 
-                let guard = request.state().lock().unwrap();
-                Ok(match guard.user_groups.get(&UserGroupId{user_id, group_id})
-                {
-                    None => response_error("user does not belong to this group"),
-                    Some(user_group_props) =>
-                    {
-                        response_data(json!({"cysh_for_id": user_group_props.santa_id}))
-                    }
-                })
-            });
-        app.at("/group/secret_santa")
-            .post(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let body: Value = request.body_json().await?;
-                let object = body.as_object().unwrap();
-                let group_id: Id = get_field(object, "group_id");
-                let admin_id: Id = get_field(object, "admin_id");
-
-                let mut guard = request.state().lock().unwrap();
-                Ok(match guard.user_groups.get(&UserGroupId{user_id: admin_id, group_id})
-                {
-                    None => response_error("user does not belong to this group"),
-                    Some(user_group_props) =>
-                    {
-                        if user_group_props.access_level != Access::Admin
-                        {
-                            response_error("its not admin")
-                        }
-                        else
-                        {
-                            *guard.groups.get_mut(&(group_id)).unwrap() = true;
-                            let group: Vec<Id> = guard.user_groups.keys().filter_map(|key|
-                                match key.group_id == group_id
-                                {
-                                    true => Some(key.user_id),
-                                    false => None,
-                                }
-                            ).collect();
-                            let santas = get_secret_santas(&group);
-                            for i in 0..group.len()
-                            {
-                                guard.user_groups.get_mut(&UserGroupId{user_id: group[i], group_id}).unwrap().santa_id = santas[i];
-                            }
-                            response_empty()
-                        }
-                    }
-                })
-            });
-        app.at("/user/update")
-            .put(|mut request: Request<Arc<Mutex<DataBase>>>| async move{
-                let body: Value = request.body_json().await?;
-                let object = body.as_object().unwrap();
-                let id : Id = get_field(object, "user_id");
-                let name: String = get_field(object, "name");
-                let mut guard = request.state().lock().unwrap();
-                Ok( if !guard.users.contains_key(&id)
-                {
-                    response_error("No such id")
-                }
-                else
-                {
-                    guard.users.entry(id).and_modify(|k| *k = name);
-                    response_empty()
-                })
-            });
+        let mut response = next.run(request).await;
+        if !response.status().is_success()
+        {
+            lock_idempotent_responses().remove(&key);
+            return Ok(response);
+        }
+        let body = response.take_body();
+        let mime = body.mime().clone();
+        let bytes = body.into_bytes().await?;
+        if let Some((_, _, stored)) = lock_idempotent_responses().get_mut(&key)
+        {
+            let location = response.header("Location").map(|values| values.last().as_str().to_string());
+            *stored = IdempotentResponse::Done(response.status(), location, mime.clone(), bytes.clone());
+        }
+        response.set_body(bytes);
+        response.set_content_type(mime);
+        Ok(response)
+    })
+}
 
-        app.at("/user/delete")
-            .delete(|mut request: Request<Arc<Mutex<DataBase>>>| async move {
-                let body: Value = request.body_json().await?;
-                let object = body.as_object().unwrap();
-                let user_id = get_field(object, "user_id");
-                let mut guard = request.state().lock().unwrap();
-                Ok(match guard.users.get(&user_id)
-                {
-                    None => response_error("This user does not exist."),
-                    Some(_name) =>
-                    {
-                        if guard.user_groups.len() > 0
-                        {
-                            let iter1 = guard.user_groups.iter();
-                            let iter2 = guard.user_groups.iter();
-                            let collection = iter1.filter(|&x| x.0.user_id == user_id);
-                            let collect_copy = iter2.filter(|&x| x.0.user_id == user_id);
-                            let closed_collect = collection.filter(|&x| guard.groups.get(&x.0.group_id).unwrap() == &true);
-                            let free_collect = collect_copy.filter(|&x| guard.groups.get(&x.0.group_id).unwrap() == &false);
-                            let mut admin_flag = false;
-                            let mut vec:Vec<Id> = Vec::new();
-                            let mut delete_vec=Vec::new();
-                            for x in free_collect
-                            {
-                                if x.1.access_level == Access::Admin && count_admins(x.0.group_id, &guard.user_groups) == 1
-                                {
-                                    admin_flag=true;
-                                    vec.push(x.0.group_id);
-                                }
-                                else 
-                                {
-                                    delete_vec.push(UserGroupId{user_id: user_id, group_id: x.0.group_id});
-                                }
-                            }   
-                            if closed_collect.count() > 0
-                            {
-                                for x in delete_vec
-                                {
-                                    guard.user_groups.remove(&x);
-                                }
-                                if admin_flag == true
-                                {
-                                    let mut string: String="User has closed groups. So he was deleted from opened groups, if he wasn't last admin. User cannot be delete from groups: ".to_string();
-                                    for x in vec
-                                    {
-                                            string+=format!("{0}, ", x).as_str();
-                                    }
-                                    string+="because of last admin.";
-                                    response_error(string.as_str())
-                                }
-                                else
-                                {
-                                    response_error("User has closed groups. So he was deleted from opened groups.")
-                                }
-                            }
-                            else 
-                            {
-                                for x in delete_vec
-                                {
-                                    guard.user_groups.remove(&x);
-                                }
-                                if admin_flag == false
-                                {
-                                    guard.users.remove(&user_id);
-                                    response_empty()
-                                }
-                                else 
-                                {
-                                    let mut string: String="User cannot be delete from groups: ".to_string();
-                                    for x in vec
-                                    {
-                                        string+=format!("{0}, ", x).as_str();
-                                    }
-                                    string+="because he is the last admin in these groups.";
-                                    response_error(string.as_str())
-                                }
-                            }
-                        }
-                        else
-                        {
-                            guard.users.remove(&user_id);
-                            response_empty()
-                        }
-                    }
-                })
-            });
-        
-        app.listen("127.0.0.1:8080").await
-    };
-    futures::executor::block_on(f)
-}
\ No newline at end of file
+// Маршрут запроса для метрик: числовые части пути заменяются на :id, чтобы /user/1 и /user/2
+// считались вместе. Запрос без подходящего маршрута tide отклоняет кодом 404 с пустым телом.
+fn route_label(path: &str, response: &Response) -> String
+{
+    if response.status() == tide::StatusCode::NotFound && response.is_empty() != Some(false)
+    {
+        return "unmatched".to_string();
+    }
+    path.split('/')
+        .map(|part| if !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit()) { ":id" } else { part })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+fn count_requests<'a>(request: Request<Arc<RwLock<DataBase>>>, next: tide::Next<'a, Arc<RwLock<DataBase>>>)
+    -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>>
+{
+    Box::pin(async move {
+        let method = request.method().to_string();
+        let path = request.url().path().to_string();
+        let response = next.run(request).await;
+        let mut route = route_label(&path, &response);
+        let mut counts = REQUEST_COUNTS.lock().unwrap_or_else(PoisonError::into_inner);
+        let known = counts.keys().any(|(_, known_route, _)| *known_route == route);
+        if !known && counts.keys().map(|(_, known_route, _)| known_route).collect::<HashSet<&String>>().len() >= METRICS_MAX_ROUTES
+        {
+            route = "other".to_string();
+        }
+        *counts.entry((method, route, response.status() as u16)).or_insert(0) += 1;
+        Ok(response)
+    })
+}
+
+// Значение метки Prometheus с экранированными \, " и переводами строк
+fn escape_label(value: &str) -> String
+{
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Метрики в текстовом формате Prometheus
+fn render_metrics(data: &DataBase) -> String
+{
+    let mut out = String::new();
+    let gauges = [
+        ("santa_users", "Number of users.", data.users.len()),
+        ("santa_groups", "Number of groups.", data.groups.len()),
+        ("santa_closed_groups", "Number of closed groups.", data.groups.values().filter(|group| group.is_closed).count()),
+    ];
+    for (name, help, value) in gauges
+    {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, value));
+    }
+    let slow_waits_help = format!("Number of state lock waits longer than {} ms.", LOCK_WAIT_WARN.as_millis());
+    let counters = [
+        ("santa_lock_acquisitions_total", "Number of state lock acquisitions.", LOCK_ACQUISITIONS.load(Ordering::Relaxed).to_string()),
+        ("santa_lock_slow_waits_total", slow_waits_help.as_str(), LOCK_SLOW_WAITS.load(Ordering::Relaxed).to_string()),
+        // Накоплено в микросекундах, Prometheus ждет секунды
+        ("santa_lock_wait_seconds_total", "Total time spent waiting for the state lock.",
+            (LOCK_WAIT_MICROS.load(Ordering::Relaxed) as f64 / 1e6).to_string()),
+    ];
+    for (name, help, value) in counters
+    {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, help, name, name, value));
+    }
+    out.push_str("# HELP santa_http_requests_total Number of handled HTTP requests.\n# TYPE santa_http_requests_total counter\n");
+    let counts = REQUEST_COUNTS.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut counts: Vec<(&(String, String, u16), &u64)> = counts.iter().collect();
+    counts.sort_unstable();
+    for ((method, route, status), count) in counts
+    {
+        out.push_str(&format!("santa_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}\n",
+            escape_label(method), escape_label(route), status, count));
+    }
+    out
+}
+
+// Выводить ли поля ответов в camelCase: SANTA_JSON_CASE=camel. По умолчанию snake_case.
+fn json_camel_case() -> bool
+{
+    std::env::var("SANTA_JSON_CASE").is_ok_and(|value| value == "camel")
+}
+
+fn snake_to_camel(key: &str) -> String
+{
+    let mut result = String::with_capacity(key.len());
+    let mut upper = false;
+    for c in key.chars()
+    {
+        if c == '_'
+        {
+            upper = true;
+        }
+        else if upper
+        {
+            result.extend(c.to_uppercase());
+            upper = false;
+        }
+        else
+        {
+            result.push(c);
+        }
+    }
+    result
+}
+
+// Переименование полей во всех вложенных объектах
+fn to_camel_case(value: Value) -> Value
+{
+    match value
+    {
+        Value::Object(object) => Value::Object(
+            object.into_iter().map(|(key, value)| (snake_to_camel(&key), to_camel_case(value))).collect()
+        ),
+        Value::Array(array) => Value::Array(array.into_iter().map(to_camel_case).collect()),
+        value => value,
+    }
+}
+
+// Необязательный список пар {"giver_id", "receiver_id"}
+fn get_pairs_field(object: &serde_json::Map<String, Value>, key: &str) -> tide::Result<Vec<(Id, Id)>>
+{
+    match object.get(key)
+    {
+        None => Ok(Vec::new()),
+        Some(value) => value.as_array().ok_or_else(|| wrong_field_type(key, "an array"))?.iter().map(|item| {
+            let pair = item.as_object().ok_or_else(|| wrong_field_type(key, "an array of objects"))?;
+            Ok((get_field(pair, "giver_id")?, get_field(pair, "receiver_id")?))
+        }).collect(),
+    }
+}
+
+// Страница списка по возрастанию ID: {"items": {ID: элемент}, "total", "offset", "limit"}
+fn page<T: serde::Serialize>(mut items: Vec<(Id, T)>, offset: Option<usize>, limit: Option<usize>) -> Value
+{
+    items.sort_unstable_by_key(|(id, _)| *id);
+    let total = items.len();
+    let offset = offset.unwrap_or(0);
+    let limit = limit.unwrap_or(PAGE_DEFAULT_LIMIT).min(PAGE_MAX_LIMIT);
+    let page: Map<String, Value> = items.into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(id, item)| (id.to_string(), json!(item)))
+        .collect();
+    json!({"items": page, "total": total, "offset": offset, "limit": limit})
+}
+
+// Все JSON тела ответов собираются здесь, чтобы SANTA_JSON_CASE=camel действовал на каждое
+fn json_body(value: Value) -> tide::Body
+{
+    let value = if json_camel_case() { to_camel_case(value) } else { value };
+    tide::Body::from_json(&value).unwrap()
+}
+
+fn response_data(value: Value) -> Response
+{
+    Response::builder(200)
+        .body(json_body(value))
+        .build()
+}
+
+// 201 Created: тело как у response_data и адрес созданного объекта в заголовке Location
+fn response_created(value: Value, location: &str) -> Response
+{
+    let mut response = response_data(value);
+    response.set_status(201);
+    response.insert_header("Location", location);
+    response
+}
+
+fn response_empty() -> Response
+{
+    Response::builder(200).build()
+}
+
+fn response_error(msg: &str) -> Response
+{
+    Response::builder(400)
+        .body(json_body(json!({"error": msg})))
+        .build()
+}
+
+// Ошибка с ID групп, из-за которых действие не выполнено полностью
+fn response_error_with_groups(msg: &str, group_ids: &[Id]) -> Response
+{
+    Response::builder(400)
+        .body(json_body(json!({"error": msg, "groups": group_ids})))
+        .build()
+}
+
+fn response_group_too_small(members: usize, min_members: usize) -> Response
+{
+    let msg = format!("group has {} members, at least {} are needed", members, min_members);
+    Response::builder(400)
+        .body(json_body(json!({"error": msg, "members": members, "min_members": min_members})))
+        .build()
+}
+
+fn response_not_found(msg: &str) -> Response
+{
+    Response::builder(404)
+        .body(json_body(json!({"error": msg})))
+        .build()
+}
+
+fn response_payload_too_large(msg: &str) -> Response
+{
+    Response::builder(413)
+        .body(json_body(json!({"error": msg})))
+        .build()
+}
+
+fn response_too_many_requests(msg: &str, retry_after: u64) -> Response
+{
+    Response::builder(429)
+        .header("Retry-After", retry_after.to_string())
+        .body(json_body(json!({"error": msg})))
+        .build()
+}
+
+// Нарушен инвариант, который сервер должен был обеспечить сам
+fn response_internal_error(msg: &str) -> Response
+{
+    Response::builder(500)
+        .body(json_body(json!({"error": msg})))
+        .build()
+}
+
+fn response_service_unavailable(value: Value) -> Response
+{
+    Response::builder(503)
+        .body(json_body(value))
+        .build()
+}
+
+fn response_conflict(msg: &str, assignments: Value) -> Response
+{
+    Response::builder(409)
+        .body(json_body(json!({"error": msg, "assignments": assignments})))
+        .build()
+}
+
+
+
+
+
+// Гости с истекшим сроком, которые так и не вступили ни в одну группу
+fn expired_guests(data: &DataBase, now: Instant) -> Vec<Id>
+{
+    data.guests.iter()
+        .filter(|(_, expires)| **expires <= now)
+        .map(|(id, _)| *id)
+        .filter(|id| !data.user_groups.keys().any(|key| key.user_id == *id))
+        .collect()
+}
+
+// Удаление гостей с истекшим сроком; true, если кто-то удален. Блокировка на запись
+// берется, только когда удалять есть кого, иначе пустой обход помечал бы состояние
+// измененным и вызывал лишнее сохранение в файл.
+fn sweep_guests(state: &Arc<RwLock<DataBase>>, now: Instant) -> bool
+{
+    if expired_guests(&read_state(state), now).is_empty()
+    {
+        return false;
+    }
+    let mut guard = write_state(state);
+    // Гость мог вступить в группу между блокировками
+    let expired = expired_guests(&guard, now);
+    for id in &expired
+    {
+        guard.guests.remove(id);
+        guard.wishlists.remove(id);
+        guard.user_tokens.retain(|_, user_id| user_id != id);
+        guard.users.remove(id);
+    }
+    !expired.is_empty()
+}
+
+// Группы пользователя по возрастанию ID: название, права пользователя и закрыта ли группа
+fn get_user_groups(user_id: Id, data: &DataBase) -> Vec<Value>
+{
+    let mut groups: Vec<(Id, &Access)> = data.user_groups.iter()
+        .filter(|(key, _)| key.user_id == user_id)
+        .map(|(key, props)| (key.group_id, &props.access_level))
+        .collect();
+    groups.sort_unstable_by_key(|(group_id, _)| *group_id);
+    groups.iter()
+        .map(|(group_id, access_level)| json!({
+            "group_id": group_id,
+            "name": data.groups[group_id].name,
+            "access_level": if **access_level == Access::Admin { "admin" } else { "user" },
+            "is_closed": data.groups[group_id].is_closed,
+        }))
+        .collect()
+}
+
+// Имя пользователя или название группы без пробелов по краям, пробельные символы внутри
+// заменяются одним пробелом. Ошибка, если имя пустое или длиннее NAME_MAX_LEN.
+fn normalize_name(name: &str) -> Result<String, String>
+{
+    let name = name.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if name.is_empty()
+    {
+        Err("bad name".to_string())
+    }
+    else if name.chars().count() > NAME_MAX_LEN
+    {
+        Err(format!("name must be at most {} characters long", NAME_MAX_LEN))
+    }
+    else
+    {
+        Ok(name)
+    }
+}
+
+// Первое имя, которое уже встречалось в списке, без учета регистра
+fn find_duplicate_name<'a>(names: impl Iterator<Item = &'a String>) -> Option<&'a String>
+{
+    let mut seen: HashSet<String> = HashSet::new();
+    names.into_iter().find(|name| !seen.insert(name.to_lowercase()))
+}
+
+fn get_member_ids(group_id: Id, user_groups: &HashMap<UserGroupId, UserGroupProps>) -> impl Iterator<Item = Id> + '_
+{
+    user_groups.keys().filter(move |key| key.group_id == group_id).map(|key| key.user_id)
+}
+
+// Единая проверка режима уникальных имен для всех изменений состава группы и имен.
+// members - ID участников группы (повторы не важны), arriving - вступающий или переименованный
+// пользователь с его именем: его имя сравнивается с остальными, и ошибка называет имя участника-тезки.
+// Без arriving проверяется, что среди members нет тезок. Возвращает ответ с ошибкой, если имена повторяются
+fn check_unique_names(unique_names: bool, members: impl IntoIterator<Item = Id>, arriving: Option<(Id, &String)>, data: &DataBase) -> Option<Response>
+{
+    if !unique_names
+    {
+        return None;
+    }
+    let member_ids: BTreeSet<Id> = members.into_iter()
+        .filter(|id| arriving.is_none_or(|(arriving_id, _)| arriving_id != *id))
+        .collect();
+    let mut names = member_ids.iter().filter_map(|id| data.users.get(id));
+    let taken = match arriving
+    {
+        None => find_duplicate_name(names),
+        Some((_, name)) => names.find(|member_name| member_name.to_lowercase() == name.to_lowercase()),
+    };
+    taken.map(|taken| name_taken_error(taken))
+}
+
+fn name_taken_error(name: &str) -> Response
+{
+    response_error(&format!("name \"{}\" is already taken in this group", name))
+}
+
+fn does_user_belong_to_group(user_id: Id, group_id: Id, user_groups: &HashMap<UserGroupId,UserGroupProps>) -> bool
+{
+    user_groups.contains_key(&UserGroupId { user_id, group_id })
+}
+
+fn count_admins(group_id: Id, user_groups: &HashMap<UserGroupId, UserGroupProps>) ->usize
+{
+    let iter = user_groups.iter();
+    let collection = iter.filter(|&x| x.0.group_id == group_id && x.1.access_level == Access::Admin);
+    collection.count()
+}
+fn is_admin(user_id: Id, group_id: Id, map: &HashMap<UserGroupId, UserGroupProps>) -> bool
+{
+    map.get(
+        &UserGroupId {
+            user_id,
+            group_id,
+        }
+    ).unwrap().access_level == Access::Admin
+}
+
+// Проведена ли жеребьевка. Закрытая группа может еще ждать ее.
+fn is_drawn(group_id: Id, user_groups: &HashMap<UserGroupId, UserGroupProps>) -> bool
+{
+    user_groups.iter().any(|(key, props)| key.group_id == group_id && props.santa_id.is_some())
+}
+
+// Соответствие ID участника группы и ID того, кому он дарит подарок
+fn get_assignments(group_id: Id, user_groups: &HashMap<UserGroupId, UserGroupProps>) -> Map<String, Value>
+{
+    user_groups.iter()
+        .filter(|(key, _)| key.group_id == group_id)
+        .filter_map(|(key, props)| props.santa_id.map(|santa_id| (key.user_id.to_string(), json!(santa_id))))
+        .collect()
+}
+
+// Участники группы, которым никто не дарит подарок.
+// До запуска Тайного Кыш Бабая это все участники группы.
+fn get_non_participants(group_id: Id, is_closed: bool, user_groups: &HashMap<UserGroupId, UserGroupProps>) -> Vec<Id>
+{
+    let members = user_groups.keys().filter(|key| key.group_id == group_id).map(|key| key.user_id);
+    let mut result: Vec<Id> = if is_closed
+    {
+        let targets: Vec<Id> = user_groups.iter()
+            .filter(|(key, _)| key.group_id == group_id)
+            .filter_map(|(_, props)| props.santa_id)
+            .collect();
+        members.filter(|user_id| !targets.contains(user_id)).collect()
+    }
+    else
+    {
+        members.collect()
+    };
+    result.sort_unstable();
+    result
+}
+
+// Короткий хеш назначений группы: первые 16 hex-символов SHA-256 от строк
+// "даритель->получатель", по одной на участника в порядке возрастания ID дарителя
+fn get_assignment_digest(group_id: Id, user_groups: &HashMap<UserGroupId, UserGroupProps>) -> String
+{
+    let mut pairs: Vec<(Id, Id)> = user_groups.iter()
+        .filter(|(key, _)| key.group_id == group_id)
+        .filter_map(|(key, props)| props.santa_id.map(|santa_id| (key.user_id, santa_id)))
+        .collect();
+    pairs.sort_unstable();
+    let text: String = pairs.iter().map(|(giver, receiver)| format!("{}->{}\n", giver, receiver)).collect();
+    Sha256::digest(text.as_bytes()).iter().take(8).map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Подпись HMAC-SHA256 в base64 ключом из переменной окружения SANTA_SIGNING_KEY
+fn sign_manifest(manifest: &str) -> Option<String>
+{
+    let key = std::env::var("SANTA_SIGNING_KEY").ok()?;
+    let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).ok()?;
+    mac.update(manifest.as_bytes());
+    Some(base64::encode(mac.finalize().into_bytes()))
+}
+
+// Длины цепочек дарения в группе по убыванию, без раскрытия пар
+fn get_cycle_lengths(group_id: Id, user_groups: &HashMap<UserGroupId, UserGroupProps>) -> Vec<usize>
+{
+    let targets: HashMap<Id, Id> = user_groups.iter()
+        .filter(|(key, _)| key.group_id == group_id)
+        .filter_map(|(key, props)| props.santa_id.map(|santa_id| (key.user_id, santa_id)))
+        .collect();
+    let mut visited: HashSet<Id> = HashSet::new();
+    let mut result = Vec::new();
+    for start in targets.keys()
+    {
+        let mut length = 0;
+        let mut current = Some(*start);
+        while let Some(user_id) = current
+        {
+            if !visited.insert(user_id)
+            {
+                break;
+            }
+            length += 1;
+            current = targets.get(&user_id).copied();
+        }
+        if length > 0
+        {
+            result.push(length);
+        }
+    }
+    result.sort_unstable_by(|a, b| b.cmp(a));
+    result
+}
+
+// Ошибка, если заранее заданные пары нельзя выполнить ни при каком назначении
+fn check_forced(group: &[Id], forced: &[(Id, Id)]) -> Result<(), &'static str>
+{
+    let givers: HashSet<Id> = forced.iter().map(|(giver, _)| *giver).collect();
+    let receivers: HashSet<Id> = forced.iter().map(|(_, receiver)| *receiver).collect();
+    if forced.iter().any(|(giver, receiver)| !group.contains(giver) || !group.contains(receiver))
+    {
+        return Err("forced pair member isn't in the group");
+    }
+    if givers.len() != forced.len() || receivers.len() != forced.len()
+    {
+        return Err("forced pairs repeat a giver or a receiver");
+    }
+    if forced.iter().any(|(giver, receiver)| giver == receiver)
+    {
+        return Err("forced pair assigns a user to themselves");
+    }
+    Ok(())
+}
+
+// Пара исключенных участников в порядке возрастания ID
+fn exclusion_pair(user_a: Id, user_b: Id) -> (Id, Id)
+{
+    (user_a.min(user_b), user_a.max(user_b))
+}
+
+// Исключения с участником, покинувшим группу, удаляются: иначе снимок группы не пройдет
+// check_snapshot, а max_cycle_length останется недоступен
+fn drop_exclusions(exclusions: &mut HashMap<Id, HashSet<(Id, Id)>>, group_id: Id, user_id: Id)
+{
+    if let Some(excluded) = exclusions.get_mut(&group_id)
+    {
+        excluded.retain(|(user_a, user_b)| *user_a != user_id && *user_b != user_id);
+        if excluded.is_empty()
+        {
+            exclusions.remove(&group_id);
+        }
+    }
+}
+
+// Назначение с заранее заданными парами даритель -> получатель (forced), в котором исключенные
+// пары (excluded) не дарят друг другу ни в одну сторону. Ищется паросочетание дарителей и получателей
+// алгоритмом Куна; без запретов получается та же цепочка, что у get_secret_santas.
+fn get_secret_santas_with_exclusions(group: &[Id], forced: &[(Id, Id)], excluded: &HashSet<(Id, Id)>) -> Result<Vec<Id>, &'static str>
+{
+    check_forced(group, forced)?;
+    if forced.iter().any(|(giver, receiver)| excluded.contains(&exclusion_pair(*giver, *receiver)))
+    {
+        return Err("forced pair is excluded");
+    }
+    let forced_targets: HashMap<Id, Id> = forced.iter().copied().collect();
+    let forced_receivers: HashSet<Id> = forced.iter().map(|(_, receiver)| *receiver).collect();
+    let allowed = |giver: Id, receiver: Id| match forced_targets.get(&giver)
+    {
+        Some(target) => *target == receiver,
+        None => giver != receiver
+            && !forced_receivers.contains(&receiver)
+            && !excluded.contains(&exclusion_pair(giver, receiver)),
+    };
+
+    // owners[j] - индекс дарителя, которому достался group[j]
+    let mut owners: Vec<Option<usize>> = vec![None; group.len()];
+    for giver in 0..group.len()
+    {
+        let mut visited = vec![false; group.len()];
+        if !find_receiver(giver, group, &allowed, &mut visited, &mut owners)
+        {
+            return Err("no valid assignment exists");
+        }
+    }
+    let targets: HashMap<Id, Id> = owners.iter().enumerate()
+        .map(|(receiver, owner)| (group[owner.unwrap()], group[receiver]))
+        .collect();
+    Ok(group.iter().map(|id| targets[id]).collect())
+}
+
+// Шаг алгоритма Куна: найти получателя для giver, при необходимости переназначив других
+fn find_receiver(giver: usize, group: &[Id], allowed: &dyn Fn(Id, Id) -> bool, visited: &mut [bool], owners: &mut [Option<usize>]) -> bool
+{
+    // Начиная с предыдущего участника, как в get_secret_santas
+    for step in 1..group.len()
+    {
+        let receiver = (giver + group.len() - step) % group.len();
+        if visited[receiver] || !allowed(group[giver], group[receiver])
+        {
+            continue;
+        }
+        visited[receiver] = true;
+        if owners[receiver].is_none_or(|owner| find_receiver(owner, group, allowed, visited, owners))
+        {
+            owners[receiver] = Some(giver);
+            return true;
+        }
+    }
+    false
+}
+
+// Назначения, которые меняются, когда leaving_id выходит из группы после жеребьевки.
+// targets - кто кому дарит. Даритель уходящего (giver) остается без получателя, а получатель
+// уходящего (receiver) - без дарителя. Сначала giver дарит receiver, меняется одна пара.
+// Если так нельзя, ищется пара a -> b, которую можно заменить на giver -> b и a -> receiver.
+// None, если ни то ни другое не позволяют исключения.
+fn repair_after_departure(targets: &HashMap<Id, Id>, leaving_id: Id, excluded: &HashSet<(Id, Id)>) -> Option<Vec<(Id, Id)>>
+{
+    let allowed = |giver: Id, receiver: Id| giver != receiver && !excluded.contains(&exclusion_pair(giver, receiver));
+    let receiver = *targets.get(&leaving_id)?;
+    let giver = *targets.iter().find(|(_, target)| **target == leaving_id)?.0;
+    if allowed(giver, receiver)
+    {
+        return Some(vec![(giver, receiver)]);
+    }
+    let mut pairs: Vec<(Id, Id)> = targets.iter()
+        .map(|(a, b)| (*a, *b))
+        .filter(|(a, b)| *a != leaving_id && *b != leaving_id)
+        .collect();
+    pairs.sort_unstable();
+    pairs.into_iter()
+        .find(|(a, b)| allowed(giver, *b) && allowed(*a, receiver))
+        .map(|(a, b)| vec![(giver, b), (a, receiver)])
+}
+
+// Сообщение об ошибке, если снимок группы нельзя импортировать
+fn check_snapshot(snapshot: &GroupSnapshot) -> Option<&'static str>
+{
+    let ids: HashSet<Id> = snapshot.members.iter().map(|member| member.id).collect();
+    let participants = snapshot.members.iter().filter(|member| !member.sits_out).count();
+    let targets: HashSet<Id> = snapshot.members.iter().filter_map(|member| member.santa_id).collect();
+    let assigned = snapshot.members.iter().filter(|member| member.santa_id.is_some()).count();
+    if ids.len() != snapshot.members.len()
+    {
+        Some("duplicate member id")
+    }
+    else if snapshot.members.iter().any(|member| normalize_name(&member.name).is_err())
+    {
+        Some("bad name")
+    }
+    // Пустое название заменяется названием по умолчанию, слишком длинное не принимается
+    else if snapshot.name.chars().any(|c| !c.is_whitespace()) && normalize_name(&snapshot.name).is_err()
+    {
+        Some("bad group name")
+    }
+    else if !snapshot.members.iter().any(|member| member.admin)
+    {
+        Some("group has no admin")
+    }
+    else if snapshot.members.iter().any(|member| member.wishlist.len() > WISHLIST_MAX_ITEMS
+        || member.wishlist.iter().any(|item| item.is_empty() || item.chars().count() > WISHLIST_MAX_ITEM_LEN))
+    {
+        Some("bad wishlist")
+    }
+    else if snapshot.exclusions.iter().any(|(user_a, user_b)| user_a == user_b || !ids.contains(user_a) || !ids.contains(user_b))
+    {
+        Some("bad exclusions")
+    }
+    // Закрытая группа без жеребьевки переносится без назначений. После жеребьевки (есть digest)
+    // снимок без spoil=true назначений не содержит, и восстановить их нельзя
+    else if snapshot.closed && assigned == 0 && snapshot.digest.is_some()
+    {
+        Some("snapshot of a closed group has no assignments")
+    }
+    else if !snapshot.closed && participants != snapshot.members.len()
+    {
+        Some("open group cannot have members out of the draw")
+    }
+    else if snapshot.closed && assigned != 0 && assigned != participants
+    {
+        Some("bad assignments")
+    }
+    else if !snapshot.closed && assigned > 0
+    {
+        Some("open group cannot have assignments")
+    }
+    else if targets.len() != assigned
+        || !targets.is_subset(&ids)
+        || snapshot.members.iter().any(|member| member.sits_out && (member.santa_id.is_some() || targets.contains(&member.id)))
+        || snapshot.members.iter().any(|member| member.santa_id == Some(member.id))
+        || snapshot.members.iter().any(|member| member.santa_id.is_some_and(|santa_id|
+            snapshot.exclusions.iter().any(|(user_a, user_b)| exclusion_pair(*user_a, *user_b) == exclusion_pair(member.id, santa_id))))
+    {
+        Some("bad assignments")
+    }
+    else
+    {
+        None
+    }
+}
+
+// Проверка назначений группы после жеребьевки: у каждого участника жеребьевки есть получатель
+// из нее, никто не дарит себе, каждый получает ровно один подарок, исключенные пары не дарят друг
+// другу. Снятые с жеребьевки не дарят и не получают. Ошибка описывает первое найденное нарушение.
+fn validate_assignment(group_id: Id, user_groups: &HashMap<UserGroupId, UserGroupProps>, excluded: &HashSet<(Id, Id)>) -> Result<(), String>
+{
+    let mut sitting_out: Vec<(Id, Option<Id>)> = user_groups.iter()
+        .filter(|(key, props)| key.group_id == group_id && props.sits_out)
+        .map(|(key, props)| (key.user_id, props.santa_id))
+        .collect();
+    sitting_out.sort_unstable();
+    if let Some((user_id, Some(santa_id))) = sitting_out.iter().find(|(_, santa_id)| santa_id.is_some())
+    {
+        return Err(format!("user {} gives to user {}, but does not take part in the draw", user_id, santa_id));
+    }
+    let mut targets: Vec<(Id, Option<Id>)> = user_groups.iter()
+        .filter(|(key, props)| key.group_id == group_id && !props.sits_out)
+        .map(|(key, props)| (key.user_id, props.santa_id))
+        .collect();
+    targets.sort_unstable();
+    let members: HashSet<Id> = targets.iter().map(|(user_id, _)| *user_id).collect();
+    let mut received: HashMap<Id, usize> = HashMap::new();
+    for (user_id, santa_id) in &targets
+    {
+        let santa_id = santa_id.ok_or_else(|| format!("user {} has no recipient", user_id))?;
+        if santa_id == *user_id
+        {
+            return Err(format!("user {} gives to themselves", user_id));
+        }
+        if sitting_out.iter().any(|(sitting_id, _)| *sitting_id == santa_id)
+        {
+            return Err(format!("user {} gives to user {}, who does not take part in the draw", user_id, santa_id));
+        }
+        if !members.contains(&santa_id)
+        {
+            return Err(format!("user {} gives to user {}, who is not in the group", user_id, santa_id));
+        }
+        if excluded.contains(&exclusion_pair(*user_id, santa_id))
+        {
+            return Err(format!("user {} gives to user {}, but the pair is excluded", user_id, santa_id));
+        }
+        *received.entry(santa_id).or_default() += 1;
+    }
+    // Получателей столько же, сколько дарящих, поэтому лишний подарок означает, что кто-то остался без подарка
+    match targets.iter().find(|(user_id, _)| received.get(user_id) != Some(&1))
+    {
+        Some((user_id, _)) => Err(format!("user {} receives {} gifts", user_id, received.get(user_id).unwrap_or(&0))),
+        None => Ok(()),
+    }
+}
+
+// Нарушенные инварианты данных; пусто, если все в порядке
+fn check_invariants(data: &DataBase) -> Vec<String>
+{
+    let mut failures = Vec::new();
+    for key in data.user_groups.keys()
+    {
+        if !data.users.contains_key(&key.user_id)
+        {
+            failures.push(format!("membership of user {} in group {} references no user", key.user_id, key.group_id));
+        }
+        if !data.groups.contains_key(&key.group_id)
+        {
+            failures.push(format!("membership of user {} in group {} references no group", key.user_id, key.group_id));
+        }
+    }
+    let mut group_ids: Vec<&Id> = data.groups.keys().collect();
+    group_ids.sort_unstable();
+    for group_id in group_ids
+    {
+        if count_admins(*group_id, &data.user_groups) == 0
+        {
+            failures.push(format!("group {} has no admin", group_id));
+        }
+        // В закрытой группе каждый дарит ровно одному другому участнику и получает ровно от одного
+        if is_drawn(*group_id, &data.user_groups)
+        {
+            let excluded = data.exclusions.get(group_id).cloned().unwrap_or_default();
+            if let Err(msg) = validate_assignment(*group_id, &data.user_groups, &excluded)
+            {
+                failures.push(format!("group {} has no valid assignment: {}", group_id, msg));
+            }
+            if data.digests.get(group_id).is_some_and(|digest| *digest != get_assignment_digest(*group_id, &data.user_groups))
+            {
+                failures.push(format!("group {} assignment does not match its digest", group_id));
+            }
+        }
+    }
+    failures
+}
+
+// Текущее время в секундах Unix
+fn unix_time() -> u64
+{
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+// Случайный токен для одноразовых ссылок и пользователей
+fn new_token() -> String
+{
+    let bytes: [u8; 16] = rand::random();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn get_secret_santas(group: &[Id]) -> Vec<Id>
+{
+    //group должна содержать не меньше 2 участников
+    //Пользователю присваивается santa_id = Id предыдущего в group
+    //Первому присваивается последний
+    let mut result = Vec::with_capacity(group.len());
+    result.push(group[group.len() - 1]);
+    result.extend_from_slice(&group[..group.len() - 1]);
+    result
+}
+
+// Разбивает group на почти равные части длиной от 2 до max_cycle_length
+// и назначает Кыш Бабаев внутри каждой части.
+// None, если такое разбиение невозможно.
+fn get_secret_santas_with_max_cycle(group: &[Id], max_cycle_length: usize) -> Option<Vec<Id>>
+{
+    if max_cycle_length < 2
+    {
+        return None;
+    }
+    let cycles = group.len().div_ceil(max_cycle_length);
+    if group.len() < 2 * cycles
+    {
+        return None;
+    }
+    let base = group.len() / cycles;
+    let extra = group.len() % cycles;
+    let mut result = Vec::with_capacity(group.len());
+    let mut start = 0;
+    for i in 0..cycles
+    {
+        let len = if i < extra { base + 1 } else { base };
+        result.extend(get_secret_santas(&group[start..start + len]));
+        start += len;
+    }
+    Some(result)
+}
+
+// Сервер со всеми middleware и маршрутами; тесты отправляют запросы прямо в него
+fn build_app(state: Arc<RwLock<DataBase>>) -> tide::Server<Arc<RwLock<DataBase>>>
+{
+    let mut app = tide::with_state(state);
+    // Первым, чтобы заголовки CORS были и у ответов остальных middleware
+    if let Some(origins) = cors_origins()
+    {
+        app.with(CorsMiddleware::new()
+            .allow_methods("GET, POST, PUT, DELETE, OPTIONS".parse::<HeaderValue>().unwrap())
+            // `*` в Access-Control-Allow-Headers не покрывает Authorization
+            .allow_headers("Content-Type, Authorization, X-User-Token, Idempotency-Key".parse::<HeaderValue>().unwrap())
+            // Без этого браузер не дает клиенту прочитать адрес созданного объекта
+            .expose_headers("Location".parse::<HeaderValue>().unwrap())
+            .allow_origin(origins));
+    }
+    app.with(count_requests);
+    app.with(limit_server_rate);
+    app.with(require_api_key);
+    app.with(limit_body_size);
+    app.with(limit_array_len);
+    app.with(log_requests);
+    app.with(check_acting_user);
+    app.with(replay_idempotent);
+    app.with(error_body);
+
+    // Routes
+    app.at("/users")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: UsersQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            let admins: HashSet<Id> = guard.user_groups.iter()
+                .filter(|(_, props)| props.access_level == Access::Admin)
+                .map(|(key, _)| key.user_id)
+                .collect();
+            // Гости выводятся только по запросу
+            let users: Vec<(Id, &String)> = guard.users.iter()
+                .filter(|(id, _)| query.guests.unwrap_or(false) || !guard.guests.contains_key(id))
+                .filter(|(_, name)| query.q.as_ref().is_none_or(|q| name.contains(q.as_str())))
+                .filter(|(id, _)| query.admin_somewhere.is_none_or(|admin| admins.contains(id) == admin))
+                .map(|(id, name)| (*id, name))
+                .collect();
+            Ok(response_data(page(users, query.offset, query.limit)))
+        });
+    app.at("/groups")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: PageQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            let groups: Vec<(Id, &GroupProps)> = guard.groups.iter().map(|(id, group)| (*id, group)).collect();
+            Ok(response_data(page(groups, query.offset, query.limit)))
+        });
+    
+    app.at("/user/create")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let input_obj = get_object(&body)?;
+            handlers::user_create(input_obj, request.state())
+        });
+    app.at("/users/create_batch")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::users_create_batch(object, request.state())
+        });
+    app.at("/group/create")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_create(object, request.state())
+        });
+    app.at("/group/join")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_join(object, request.state())
+        });
+    app.at("/group/unadmin")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_unadmin(object, request.state())
+        });
+    app.at("/group/delete")
+        .delete(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_delete(object, request.state())
+        });
+    app.at("/group/merge")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_merge(object, request.state())
+        });
+    app.at("/group/make_admin")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_make_admin(object, request.state())
+        });
+    app.at("/group/transfer_admin")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_transfer_admin(object, request.state())
+        });
+    app.at("/group/quit")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_quit(object, request.state())
+        });
+    app.at("/group/set_budget")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_set_budget(object, request.state())
+        });
+    app.at("/group/rename")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_rename(object, request.state())
+        });
+    app.at("/group/invite")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_invite(object, request.state())
+        });
+    app.at("/group/set_private")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_set_private(object, request.state())
+        });
+    app.at("/group/set_unique_names")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_set_unique_names(object, request.state())
+        });
+    app.at("/group/exclude")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_exclude(object, request.state())
+        });
+    app.at("/group/kick")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_kick(object, request.state())
+        });
+    app.at("/group/undo")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_undo(object, request.state())
+        });
+    app.at("/group/target_by_id/:user_id/:group_id")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move{
+            // Нечисловой или слишком большой ID - ошибка запроса, а не паника
+            let user_id = match request.param("user_id")?.parse::<Id>()
+            {
+                Ok(user_id) => user_id,
+                Err(_) => return Ok(response_error("Wrong format user id")),
+            };
+            let group_id = match request.param("group_id")?.parse::<Id>()
+            {
+                Ok(group_id) => group_id,
+                Err(_) => return Ok(response_error("Wrong format group id")),
+            };
+            if let Some(retry_after) = reveal_retry_after(request.peer_addr(), group_id)
+            {
+                return Ok(response_too_many_requests("too many reveal requests, try again later", retry_after));
+            }
+
+            let mut guard = write_state(request.state());
+            if !guard.groups.contains_key(&group_id)
+            {
+                return Ok(response_not_found("no such group"));
+            }
+            let user_group_id = UserGroupId{user_id, group_id};
+            Ok(match guard.user_groups.get(&user_group_id).map(|props| (props.sits_out, props.santa_id))
+            {
+                None => response_error("user does not belong to this group"),
+                Some((true, _)) => response_error("user does not take part in the draw"),
+                Some((false, None)) => response_error("secret santa has not started yet"),
+                Some((false, Some(santa_id))) =>
+                {
+                    guard.user_groups.get_mut(&user_group_id).unwrap().revealed = true;
+                    response_data(json!({
+                        "cysh_for_id": santa_id,
+                        "cysh_for_name": guard.users.get(&santa_id),
+                        "cysh_for_wishlist": guard.wishlists.get(&santa_id).cloned().unwrap_or_default(),
+                        "budget": guard.groups[&group_id].budget,
+                    }))
+                }
+            })
+        });
+    app.at("/group/close")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_close(object, request.state())
+        });
+    app.at("/group/reopen")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_reopen(object, request.state())
+        });
+    app.at("/group/secret_santa")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_secret_santa(object, request.state())
+        });
+    app.at("/group/remove_member_from_draw")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_remove_member_from_draw(object, request.state())
+        });
+    app.at("/group/reassign")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_reassign(object, request.state())
+        });
+    app.at("/group/reveal_token")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_reveal_token(object, request.state())
+        });
+    app.at("/reveal")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: TokenQuery = request.query()?;
+
+            let mut guard = write_state(request.state());
+            // Токен действует один раз
+            Ok(match guard.reveal_tokens.remove(&query.token)
+            {
+                None => response_not_found("no such token"),
+                Some(user_group_id) => match guard.user_groups.get(&user_group_id).and_then(|props| props.santa_id)
+                {
+                    None => response_error("user does not belong to this group"),
+                    Some(santa_id) =>
+                    {
+                        guard.user_groups.get_mut(&user_group_id).unwrap().revealed = true;
+                        response_data(json!({
+                            "group_id": user_group_id.group_id,
+                            "cysh_for_id": santa_id,
+                            "cysh_for_name": guard.users.get(&santa_id),
+                            "cysh_for_wishlist": guard.wishlists.get(&santa_id).cloned().unwrap_or_default(),
+                            "budget": guard.groups.get(&user_group_id.group_id).and_then(|group| group.budget),
+                        }))
+                    }
+                }
+            })
+        });
+    app.at("/group/non_participants")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: GroupQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            Ok(match guard.groups.get(&query.group_id)
+            {
+                None => response_not_found("no such group"),
+                Some(group) =>
+                {
+                    response_data(json!({"non_participants": get_non_participants(query.group_id, group.is_closed, &guard.user_groups)}))
+                }
+            })
+        });
+    app.at("/group/members")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: GroupQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            Ok(match guard.groups.get(&query.group_id)
+            {
+                None => response_not_found("no such group"),
+                Some(group) =>
+                {
+                    let mut members: Vec<(Id, &Access)> = guard.user_groups.iter()
+                        .filter(|(key, _)| key.group_id == query.group_id)
+                        .map(|(key, props)| (key.user_id, &props.access_level))
+                        .collect();
+                    members.sort_unstable_by_key(|(user_id, _)| *user_id);
+                    let members: Vec<Value> = members.iter()
+                        .map(|(user_id, access_level)| json!({
+                            "user_id": user_id,
+                            "name": guard.users.get(user_id),
+                            "access_level": if **access_level == Access::Admin { "admin" } else { "user" },
+                        }))
+                        .collect();
+                    response_data(json!({"name": group.name, "members": members}))
+                }
+            })
+        });
+    app.at("/group/contains")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: MembershipQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            Ok(if !guard.groups.contains_key(&query.group_id)
+            {
+                response_not_found("no such group")
+            }
+            else
+            {
+                let access_level = guard.user_groups.get(&UserGroupId{user_id: query.user_id, group_id: query.group_id})
+                    .map(|props| if props.access_level == Access::Admin { "admin" } else { "user" });
+                response_data(json!({"member": access_level.is_some(), "access_level": access_level}))
+            })
+        });
+    app.at("/group/stats")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: GroupQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            Ok(match guard.groups.get(&query.group_id)
+            {
+                None => response_not_found("no such group"),
+                Some(group) =>
+                {
+                    let members: Vec<Id> = guard.user_groups.keys()
+                        .filter(|key| key.group_id == query.group_id)
+                        .map(|key| key.user_id)
+                        .collect();
+                    let with_wishlist = members.iter()
+                        .filter(|user_id| guard.wishlists.get(user_id).is_some_and(|wishlist| !wishlist.is_empty()))
+                        .count();
+                    response_data(json!({
+                        "members": members.len(),
+                        "admins": count_admins(query.group_id, &guard.user_groups),
+                        "is_closed": group.is_closed,
+                        "unique_names": group.unique_names,
+                        "private": group.is_private,
+                        "drawn": is_drawn(query.group_id, &guard.user_groups),
+                        "with_wishlist": with_wishlist,
+                    }))
+                }
+            })
+        });
+    app.at("/group/assignments")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: AdminGroupQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            if !guard.groups.contains_key(&query.group_id)
+            {
+                return Ok(response_not_found("no such group"));
+            }
+            Ok(match guard.user_groups.get(&UserGroupId{user_id: query.admin_id, group_id: query.group_id})
+            {
+                None => response_error("user does not belong to this group"),
+                Some(user_group_props) =>
+                {
+                    if user_group_props.access_level != Access::Admin
+                    {
+                        response_error("This user is not an admin.")
+                    }
+                    else if !is_drawn(query.group_id, &guard.user_groups)
+                    {
+                        response_error("secret santa has not started yet")
+                    }
+                    else
+                    {
+                        let mut members: Vec<Id> = guard.user_groups.keys()
+                            .filter(|key| key.group_id == query.group_id)
+                            .map(|key| key.user_id)
+                            .collect();
+                        members.sort_unstable();
+                        let timestamp = unix_time();
+                        let manifest = json!({
+                            "group_id": query.group_id,
+                            "members": members,
+                            "assignments": get_assignments(query.group_id, &guard.user_groups),
+                            "timestamp": timestamp,
+                        }).to_string();
+                        match sign_manifest(&manifest)
+                        {
+                            // Ошибка настройки сервера, а не запроса
+                            None => response_service_unavailable(json!({"error": "signing key is not configured"})),
+                            Some(signature) => response_data(json!({"manifest": manifest, "signature": signature})),
+                        }
+                    }
+                }
+            })
+        })
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_assignments(object, request.state())
+        });
+    app.at("/group/snapshot")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: SnapshotQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            if !guard.groups.contains_key(&query.group_id)
+            {
+                return Ok(response_not_found("no such group"));
+            }
+            Ok(match guard.user_groups.get(&UserGroupId{user_id: query.admin_id, group_id: query.group_id})
+            {
+                None => response_error("user does not belong to this group"),
+                Some(user_group_props) =>
+                {
+                    if user_group_props.access_level != Access::Admin
+                    {
+                        response_error("This user is not an admin.")
+                    }
+                    else
+                    {
+                        let spoil = query.spoil.unwrap_or(false);
+                        let mut members: Vec<MemberSnapshot> = guard.user_groups.iter()
+                            .filter(|(key, _)| key.group_id == query.group_id)
+                            .map(|(key, props)| MemberSnapshot {
+                                id: key.user_id,
+                                name: guard.users[&key.user_id].clone(),
+                                admin: props.access_level == Access::Admin,
+                                wishlist: guard.wishlists.get(&key.user_id).cloned().unwrap_or_default(),
+                                santa_id: if spoil { props.santa_id } else { None },
+                                sits_out: props.sits_out,
+                            })
+                            .collect();
+                        members.sort_unstable_by_key(|member| member.id);
+                        let mut exclusions: Vec<(Id, Id)> = guard.exclusions.get(&query.group_id)
+                            .map(|excluded| excluded.iter().copied().collect())
+                            .unwrap_or_default();
+                        exclusions.sort_unstable();
+                        let group = &guard.groups[&query.group_id];
+                        response_data(json!(GroupSnapshot {
+                            group_id: query.group_id,
+                            name: group.name.clone(),
+                            closed: group.is_closed,
+                            unique_names: group.unique_names,
+                            private: group.is_private,
+                            budget: group.budget,
+                            members,
+                            exclusions,
+                            digest: guard.digests.get(&query.group_id).cloned(),
+                        }))
+                    }
+                }
+            })
+        });
+    app.at("/group/import")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::group_import(object, request.state())
+        });
+    app.at("/group/validate")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: AdminGroupQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            if !guard.groups.contains_key(&query.group_id)
+            {
+                return Ok(response_not_found("no such group"));
+            }
+            Ok(match guard.user_groups.get(&UserGroupId{user_id: query.admin_id, group_id: query.group_id})
+            {
+                None => response_error("user does not belong to this group"),
+                Some(user_group_props) =>
+                {
+                    if user_group_props.access_level != Access::Admin
+                    {
+                        response_error("This user is not an admin.")
+                    }
+                    else if !is_drawn(query.group_id, &guard.user_groups)
+                    {
+                        response_error("secret santa has not started yet")
+                    }
+                    else
+                    {
+                        let excluded = guard.exclusions.get(&query.group_id).cloned().unwrap_or_default();
+                        match validate_assignment(query.group_id, &guard.user_groups, &excluded)
+                        {
+                            Ok(()) => response_data(json!({"valid": true})),
+                            Err(msg) => response_data(json!({"valid": false, "error": msg})),
+                        }
+                    }
+                }
+            })
+        });
+    app.at("/group/graph")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: AdminGroupQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            if !guard.groups.contains_key(&query.group_id)
+            {
+                return Ok(response_not_found("no such group"));
+            }
+            Ok(match guard.user_groups.get(&UserGroupId{user_id: query.admin_id, group_id: query.group_id})
+            {
+                None => response_error("user does not belong to this group"),
+                Some(user_group_props) =>
+                {
+                    if user_group_props.access_level != Access::Admin
+                    {
+                        response_error("This user is not an admin.")
+                    }
+                    else if !is_drawn(query.group_id, &guard.user_groups)
+                    {
+                        response_error("secret santa has not started yet")
+                    }
+                    else
+                    {
+                        let mut members: Vec<(Id, Option<Id>)> = guard.user_groups.iter()
+                            .filter(|(key, _)| key.group_id == query.group_id)
+                            .map(|(key, props)| (key.user_id, props.santa_id))
+                            .collect();
+                        members.sort_unstable();
+                        let nodes: Vec<Value> = members.iter()
+                            .map(|(user_id, _)| json!({"id": user_id, "name": guard.users.get(user_id)}))
+                            .collect();
+                        let edges: Vec<Value> = members.iter()
+                            .filter_map(|(user_id, santa_id)| santa_id.map(|santa_id| json!({"from": user_id, "to": santa_id})))
+                            .collect();
+                        response_data(json!({"nodes": nodes, "edges": edges}))
+                    }
+                }
+            })
+        });
+    app.at("/group/cycle_info")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: AdminGroupQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            if !guard.groups.contains_key(&query.group_id)
+            {
+                return Ok(response_not_found("no such group"));
+            }
+            Ok(match guard.user_groups.get(&UserGroupId{user_id: query.admin_id, group_id: query.group_id})
+            {
+                None => response_error("user does not belong to this group"),
+                Some(user_group_props) =>
+                {
+                    if user_group_props.access_level != Access::Admin
+                    {
+                        response_error("This user is not an admin.")
+                    }
+                    else if !is_drawn(query.group_id, &guard.user_groups)
+                    {
+                        response_error("secret santa has not started yet")
+                    }
+                    else
+                    {
+                        response_data(json!({"cycles": get_cycle_lengths(query.group_id, &guard.user_groups)}))
+                    }
+                }
+            })
+        });
+    app.at("/group/giver")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: GiverQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            if !guard.groups.contains_key(&query.group_id)
+            {
+                return Ok(response_not_found("no such group"));
+            }
+            Ok(match guard.user_groups.get(&UserGroupId{user_id: query.admin_id, group_id: query.group_id})
+            {
+                None => response_error("user does not belong to this group"),
+                Some(user_group_props) =>
+                {
+                    if user_group_props.access_level != Access::Admin
+                    {
+                        response_error("This user is not an admin.")
+                    }
+                    else if !is_drawn(query.group_id, &guard.user_groups)
+                    {
+                        response_error("secret santa has not started yet")
+                    }
+                    else
+                    {
+                        let giver = guard.user_groups.iter().find(|(key, props)|
+                            key.group_id == query.group_id && props.santa_id == Some(query.receiver_id)
+                        );
+                        match giver
+                        {
+                            None => response_not_found("nobody gives a gift to this user"),
+                            Some((key, _)) => response_data(json!({"giver_id": key.user_id})),
+                        }
+                    }
+                }
+            })
+        });
+    app.at("/group/wait_closed")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: GroupQuery = request.query()?;
+            let deadline = Instant::now() + WAIT_CLOSED_TIMEOUT;
+            loop
+            {
+                // Подписка до проверки, чтобы не пропустить закрытие между ними
+                let listener = GROUP_CLOSED.listen();
+                {
+                    let guard = read_state(request.state());
+                    if !guard.groups.contains_key(&query.group_id)
+                    {
+                        return Ok(response_not_found("no such group"));
+                    }
+                    if is_drawn(query.group_id, &guard.user_groups)
+                    {
+                        return Ok(response_data(json!({"closed": true})));
+                    }
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if async_std::future::timeout(remaining, listener).await.is_err()
+                {
+                    return Ok(Response::builder(204).build());
+                }
+            }
+        });
+    // Только размеры таблиц, без обхода данных, как в /healthz
+    app.at("/health")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let guard = read_state(request.state());
+            Ok(response_data(json!({"status": "ok", "users": guard.users.len(), "groups": guard.groups.len()})))
+        });
+    app.at("/metrics")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let metrics = render_metrics(&read_state(request.state()));
+            Ok(Response::builder(200)
+                .content_type("text/plain; version=0.0.4")
+                .body(metrics)
+                .build())
+        });
+    app.at("/healthz")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let failures = check_invariants(&read_state(request.state()));
+            Ok(if failures.is_empty()
+            {
+                response_data(json!({"status": "ok"}))
+            }
+            else
+            {
+                response_service_unavailable(json!({"status": "unhealthy", "failures": failures}))
+            })
+        });
+    app.at("/user/groups")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let query: UserQuery = request.query()?;
+
+            let guard = read_state(request.state());
+            Ok(if !guard.users.contains_key(&query.user_id)
+            {
+                response_not_found("no such user")
+            }
+            else
+            {
+                response_data(json!(get_user_groups(query.user_id, &guard)))
+            })
+        });
+    app.at("/user/:id")
+        .get(|request: Request<Arc<RwLock<DataBase>>>| async move {
+            let user_id: Id = match request.param("id")?.parse()
+            {
+                Err(_) => return Ok(response_error("Wrong format user id")),
+                Ok(user_id) => user_id,
+            };
+
+            let guard = read_state(request.state());
+            Ok(match guard.users.get(&user_id)
+            {
+                None => response_not_found("no such user"),
+                Some(name) => response_data(json!({"id": user_id, "name": name, "groups": get_user_groups(user_id, &guard)})),
+            })
+        });
+    app.at("/user/update")
+        .put(|mut request: Request<Arc<RwLock<DataBase>>>| async move{
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::user_update(object, request.state())
+        });
+    // То же для клиентов, которые не умеют отправлять PUT
+    app.at("/user/rename")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::user_update(object, request.state())
+        });
+
+    app.at("/user/wishlist")
+        .post(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::user_wishlist(object, request.state())
+        });
+    app.at("/user/delete")
+        .delete(|mut request: Request<Arc<RwLock<DataBase>>>| async move {
+            let body: Value = request.body_json().await?;
+            let object = get_object(&body)?;
+            handlers::user_delete(object, request.state())
+        });
+    app
+}
+
+// Ждет SIGINT или SIGTERM и записывает несохраненные изменения в SANTA_DATA_FILE, если он задан.
+// Возвращает код выхода и блокировку на чтение, которую нужно держать до выхода.
+fn save_on_shutdown<'a>(signals: &mut Signals, state: &'a Arc<RwLock<DataBase>>) -> (i32, RwLockReadGuard<'a, DataBase>)
+{
+    signals.forever().next();
+    let guard = read_state(state);
+    let code = match data_file().map(|path| (guard.save_to_file(&path), path))
+    {
+        Some((Err(error), path)) =>
+        {
+            eprintln!("cannot save state to {}: {}", path, error);
+            1
+        }
+        _ => 0,
+    };
+    (code, guard)
+}
+
+fn main() -> Result<(), std::io::Error> 
+{
+    let f = async {
+        // Неверный адрес обнаруживается до загрузки данных
+        let addr = bind_addr()?;
+        let data = match data_file()
+        {
+            Some(path) if std::path::Path::new(&path).exists() => DataBase::load_from_file(&path)?,
+            _ => DataBase::new(),
+        };
+        let state = Arc::new(RwLock::new(data));
+        if let Some(path) = data_file()
+        {
+            let save_state = state.clone();
+            async_std::task::spawn(async move {
+                loop
+                {
+                    async_std::task::sleep(SAVE_INTERVAL).await;
+                    if STATE_DIRTY.swap(false, Ordering::Relaxed)
+                    {
+                        if let Err(error) = read_state(&save_state).save_to_file(&path)
+                        {
+                            STATE_DIRTY.store(true, Ordering::Relaxed);
+                            eprintln!("cannot save state to {}: {}", path, error);
+                        }
+                    }
+                }
+            });
+        }
+        // Блокировка на чтение остается захваченной до выхода, поэтому после сохранения
+        // обработчики уже ничего не изменят
+        let shutdown_state = state.clone();
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        std::thread::spawn(move || {
+            let (code, _guard) = save_on_shutdown(&mut signals, &shutdown_state);
+            std::process::exit(code);
+        });
+        let sweep_state = state.clone();
+        async_std::task::spawn(async move {
+            loop
+            {
+                async_std::task::sleep(GUEST_SWEEP_INTERVAL).await;
+                sweep_guests(&sweep_state, Instant::now());
+            }
+        });
+        let app = build_app(state);
+
+        // Адрес выводится, когда порт уже занят сервером
+        let mut listener = addr.to_listener()?;
+        listener.bind(app).await?;
+        println!("listening on http://{}", addr);
+        listener.accept().await
+    };
+    futures::executor::block_on(f)
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use tide::http::{Method, Url};
+
+    // Настройки читаются из переменных окружения при каждом запросе, а окружение общее для всех тестов.
+    // Тест, меняющий окружение, держит ENV_LOCK на запись, остальные запросы - на чтение,
+    // поэтому видят только значения по умолчанию.
+    static ENV_LOCK: RwLock<()> = RwLock::new(());
+
+    pub(crate) fn default_env() -> std::sync::RwLockReadGuard<'static, ()>
+    {
+        ENV_LOCK.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Переменные окружения на время теста; при удалении значения сбрасываются
+    pub(crate) struct EnvVars
+    {
+        keys: Vec<&'static str>,
+        _guard: std::sync::RwLockWriteGuard<'static, ()>,
+    }
+
+    impl Drop for EnvVars
+    {
+        fn drop(&mut self)
+        {
+            for key in &self.keys
+            {
+                std::env::remove_var(key);
+            }
+            // Лимит, суженный тестом, не должен задевать следующие запросы
+            SERVER_RATE_TAT.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn set_env(vars: &[(&'static str, &str)]) -> EnvVars
+    {
+        let guard = ENV_LOCK.write().unwrap_or_else(PoisonError::into_inner);
+        for (key, value) in vars
+        {
+            std::env::set_var(key, value);
+        }
+        EnvVars{keys: vars.iter().map(|(key, _)| *key).collect(), _guard: guard}
+    }
+
+    pub(crate) fn new_request(method: Method, path: &str, body: Option<Value>) -> tide::http::Request
+    {
+        let mut request = tide::http::Request::new(method, Url::parse(&format!("http://localhost{}", path)).unwrap());
+        if let Some(body) = body
+        {
+            request.set_body(tide::Body::from_json(&body).unwrap());
+        }
+        request
+    }
+
+    // Запрос через все middleware и маршруты без открытия порта
+    pub(crate) fn respond(app: &tide::Server<Arc<RwLock<DataBase>>>, request: tide::http::Request) -> tide::http::Response
+    {
+        futures::executor::block_on(app.respond(request)).unwrap()
+    }
+
+    pub(crate) fn response_json(response: &mut tide::http::Response) -> Value
+    {
+        let bytes = futures::executor::block_on(response.body_bytes()).unwrap();
+        if bytes.is_empty() { Value::Null } else { serde_json::from_slice(&bytes).unwrap() }
+    }
+
+    // Запрос с настройками по умолчанию: код ответа и JSON тело (null, если тела нет)
+    pub(crate) fn send(app: &tide::Server<Arc<RwLock<DataBase>>>, method: Method, path: &str, body: Option<Value>) -> (u16, Value)
+    {
+        let _env = default_env();
+        send_with_env(app, method, path, body)
+    }
+
+    // То же без ENV_LOCK, для теста, который уже задал окружение через set_env
+    pub(crate) fn send_with_env(app: &tide::Server<Arc<RwLock<DataBase>>>, method: Method, path: &str, body: Option<Value>) -> (u16, Value)
+    {
+        let mut response = respond(app, new_request(method, path, body));
+        (response.status() as u16, response_json(&mut response))
+    }
+
+    pub(crate) fn new_app() -> tide::Server<Arc<RwLock<DataBase>>>
+    {
+        build_app(Arc::new(RwLock::new(DataBase::new())))
+    }
+
+    fn error_value(msg: &str) -> Value
+    {
+        json!({"error": msg})
+    }
+
+    fn post(app: &tide::Server<Arc<RwLock<DataBase>>>, path: &str, body: Value) -> (u16, Value)
+    {
+        send(app, Method::Post, path, Some(body))
+    }
+
+    fn get(app: &tide::Server<Arc<RwLock<DataBase>>>, path: &str) -> (u16, Value)
+    {
+        send(app, Method::Get, path, None)
+    }
+
+    // Группа из count новых пользователей, первый - администратор; ID группы и участников
+    fn app_group(app: &tide::Server<Arc<RwLock<DataBase>>>, count: usize) -> (Id, Vec<Id>)
+    {
+        let users: Vec<Id> = (0..count)
+            .map(|index| post(app, "/user/create", json!({"name": format!("user {}", index)})).1["id"].as_u64().unwrap() as Id)
+            .collect();
+        let (status, body) = post(app, "/group/create", json!({"creator_id": users[0], "name": "group", "members": &users[1..]}));
+        assert_eq!(status, 201);
+        (body["group_id"].as_u64().unwrap() as Id, users)
+    }
+
+    // Участники 0..count в случайном порядке, как их перемешивает /group/secret_santa
+    fn shuffled_group(count: Id, seed: u64) -> Vec<Id>
+    {
+        let mut group: Vec<Id> = (0..count).collect();
+        group.shuffle(&mut StdRng::seed_from_u64(seed));
+        group
+    }
+
+    // Проверка, что santas - перестановка без назначений самому себе, и соответствие даритель -> получатель
+    fn check_derangement(group: &[Id], santas: &[Id]) -> HashMap<Id, Id>
+    {
+        let targets: HashMap<Id, Id> = group.iter().copied().zip(santas.iter().copied()).collect();
+        let receivers: HashSet<Id> = santas.iter().copied().collect();
+        assert_eq!(receivers.len(), group.len());
+        assert!(group.iter().all(|id| receivers.contains(id)));
+        assert!(targets.iter().all(|(giver, receiver)| giver != receiver));
+        targets
+    }
+
+    #[test]
+    fn excluded_pairs_never_gift_each_other()
+    {
+        let excluded: HashSet<(Id, Id)> = [exclusion_pair(0, 1), exclusion_pair(2, 3), exclusion_pair(0, 4)].into_iter().collect();
+        for seed in 0..500
+        {
+            let group = shuffled_group(6, seed);
+            let santas = get_secret_santas_with_exclusions(&group, &[], &excluded).unwrap();
+            let targets = check_derangement(&group, &santas);
+            assert!(targets.iter().all(|(giver, receiver)| !excluded.contains(&exclusion_pair(*giver, *receiver))), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn exclusions_without_assignment_fail()
+    {
+        let excluded: HashSet<(Id, Id)> = [exclusion_pair(0, 1)].into_iter().collect();
+        assert_eq!(get_secret_santas_with_exclusions(&[0, 1], &[], &excluded), Err("no valid assignment exists"));
+        // 2 должен дарить и 0, и 1, если они не дарят друг другу
+        assert_eq!(get_secret_santas_with_exclusions(&[0, 1, 2], &[], &excluded), Err("no valid assignment exists"));
+    }
+
+    #[test]
+    fn no_exclusions_give_the_plain_chain()
+    {
+        let group = shuffled_group(7, 1);
+        assert_eq!(get_secret_santas_with_exclusions(&group, &[], &HashSet::new()), Ok(get_secret_santas(&group)));
+    }
+
+    #[test]
+    fn departure_repair_respects_exclusions()
+    {
+        // 0 -> 1 -> 2 -> 3 -> 4 -> 0, уходит 2: 1 -> 3 нельзя, поэтому 4 -> 0 меняется на 1 -> 0 и 4 -> 3
+        let targets: HashMap<Id, Id> = [(0, 1), (1, 2), (2, 3), (3, 4), (4, 0)].into_iter().collect();
+        let excluded: HashSet<(Id, Id)> = [exclusion_pair(1, 3)].into_iter().collect();
+        assert_eq!(repair_after_departure(&targets, 2, &HashSet::new()), Some(vec![(1, 3)]));
+        assert_eq!(repair_after_departure(&targets, 2, &excluded), Some(vec![(1, 0), (4, 3)]));
+        // В 0 -> 1 -> 2 -> 3 -> 0 замена пар оставила бы кого-то дарить себе
+        let targets: HashMap<Id, Id> = [(0, 1), (1, 2), (2, 3), (3, 0)].into_iter().collect();
+        assert_eq!(repair_after_departure(&targets, 2, &excluded), None);
+    }
+
+    #[test]
+    fn forced_pair_is_kept()
+    {
+        for seed in 0..200
+        {
+            let group = shuffled_group(6, seed);
+            let santas = get_secret_santas_with_exclusions(&group, &[(0, 3)], &HashSet::new()).unwrap();
+            assert_eq!(check_derangement(&group, &santas)[&0], 3, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn forced_pairs_respect_exclusions()
+    {
+        let excluded: HashSet<(Id, Id)> = [exclusion_pair(1, 2), exclusion_pair(4, 5)].into_iter().collect();
+        for seed in 0..200
+        {
+            let group = shuffled_group(6, seed);
+            let santas = get_secret_santas_with_exclusions(&group, &[(0, 1), (2, 4)], &excluded).unwrap();
+            let targets = check_derangement(&group, &santas);
+            assert_eq!(targets[&0], 1);
+            assert_eq!(targets[&2], 4);
+            assert!(targets.iter().all(|(giver, receiver)| !excluded.contains(&exclusion_pair(*giver, *receiver))), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn only_mutual_pairs_satisfy_exclusions()
+    {
+        // Единственные назначения: 0 <-> 2 и 1 <-> 3
+        let excluded: HashSet<(Id, Id)> = [exclusion_pair(0, 1), exclusion_pair(2, 3), exclusion_pair(0, 3)].into_iter().collect();
+        for seed in 0..50
+        {
+            let group = shuffled_group(4, seed);
+            let santas = get_secret_santas_with_exclusions(&group, &[], &excluded).unwrap();
+            let targets = check_derangement(&group, &santas);
+            assert_eq!(targets, [(0, 2), (2, 0), (1, 3), (3, 1)].into_iter().collect(), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn forced_mutual_pair_is_kept()
+    {
+        let group = shuffled_group(4, 0);
+        let santas = get_secret_santas_with_exclusions(&group, &[(0, 1), (1, 0)], &HashSet::new()).unwrap();
+        let targets = check_derangement(&group, &santas);
+        assert_eq!((targets[&0], targets[&1], targets[&2], targets[&3]), (1, 0, 3, 2));
+    }
+
+    #[test]
+    fn forced_pairs_leaving_one_user_fail()
+    {
+        // Цепочка 0 -> 1 -> 2 -> 0 оставляет участнику 3 только его самого
+        let group = shuffled_group(4, 0);
+        assert_eq!(get_secret_santas_with_exclusions(&group, &[(0, 1), (1, 2), (2, 0)], &HashSet::new()), Err("no valid assignment exists"));
+    }
+
+    #[test]
+    fn cycles_respect_max_cycle_length()
+    {
+        for count in 2..=12
+        {
+            for max_cycle_length in 0..=6
+            {
+                let group = shuffled_group(count, count as u64);
+                let santas = get_secret_santas_with_max_cycle(&group, max_cycle_length);
+                // Каждая цепочка - хотя бы из двух участников
+                let possible = max_cycle_length >= 2 && count as usize >= 2 * (count as usize).div_ceil(max_cycle_length);
+                assert_eq!(santas.is_some(), possible, "{} members, max {}", count, max_cycle_length);
+                let Some(santas) = santas else { continue };
+                let targets = check_derangement(&group, &santas);
+                for start in &group
+                {
+                    let mut length = 1;
+                    let mut current = targets[start];
+                    while current != *start
+                    {
+                        current = targets[&current];
+                        length += 1;
+                    }
+                    assert!((2..=max_cycle_length).contains(&length), "{} members, max {}: cycle of {}", count, max_cycle_length, length);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn draw_enforces_max_cycle_length()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 7);
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        let draw = |max_cycle_length: usize| post(&app, "/group/secret_santa", json!({"admin_id": users[0], "group_id": group_id, "max_cycle_length": max_cycle_length}));
+        assert_eq!(draw(2), (400, error_value("cannot split the group into cycles no longer than max_cycle_length")));
+        assert_eq!(draw(3).0, 200);
+        let (_, body) = get(&app, &format!("/group/cycle_info?group_id={}&admin_id={}", group_id, users[0]));
+        assert_eq!(body["cycles"], json!([3, 2, 2]));
+    }
+
+    #[test]
+    fn cycle_info_splits_a_crafted_assignment()
+    {
+        let state = Arc::new(RwLock::new(DataBase::new()));
+        let app = build_app(state.clone());
+        let (group_id, users) = app_group(&app, 9);
+        let path = format!("/group/cycle_info?group_id={}&admin_id={}", group_id, users[0]);
+        assert_eq!(get(&app, &path), (400, error_value("secret santa has not started yet")));
+
+        // 0 -> 1 -> 0, 2 -> 3 -> 4 -> 2, 5 -> 6 -> 7 -> 8 -> 5
+        let chains: [&[usize]; 3] = [&[0, 1], &[2, 3, 4], &[5, 6, 7, 8]];
+        {
+            let mut guard = state.write().unwrap();
+            guard.groups.get_mut(&group_id).unwrap().is_closed = true;
+            for chain in chains
+            {
+                for (index, giver) in chain.iter().enumerate()
+                {
+                    let receiver = chain[(index + 1) % chain.len()];
+                    guard.user_groups.get_mut(&UserGroupId{user_id: users[*giver], group_id}).unwrap().santa_id = Some(users[receiver]);
+                }
+            }
+        }
+        assert_eq!(get(&app, &path), (200, json!({"cycles": [4, 3, 2]})));
+        assert_eq!(get(&app, &format!("/group/cycle_info?group_id={}&admin_id={}", group_id, users[1])).1, error_value("This user is not an admin."));
+    }
+
+    #[test]
+    fn metrics_export_lock_wait_time()
+    {
+        let metric = |name: &str| -> f64
+        {
+            let metrics = render_metrics(&DataBase::new());
+            metrics.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')).unwrap().parse().unwrap()
+        };
+        let metrics = render_metrics(&DataBase::new());
+        assert!(metrics.contains("# TYPE santa_lock_wait_seconds_total counter\n"));
+        assert!(metrics.contains(&format!("waits longer than {} ms.", LOCK_WAIT_WARN.as_millis())));
+        let (waited, slow_waits) = (metric("santa_lock_wait_seconds_total"), metric("santa_lock_slow_waits_total"));
+
+        // Запросы ждут, пока блокировка занята дольше LOCK_WAIT_WARN.
+        // Окружение захватывается здесь, чтобы потоки ждали только блокировку состояния.
+        let _env = default_env();
+        let state = Arc::new(RwLock::new(DataBase::new()));
+        let app = build_app(state.clone());
+        let guard = state.write().unwrap();
+        let requests: Vec<_> = (0..3)
+            .map(|_|
+            {
+                let app = app.clone();
+                std::thread::spawn(move || respond(&app, new_request(Method::Get, "/users", None)).status() as u16)
+            })
+            .collect();
+        std::thread::sleep(LOCK_WAIT_WARN * 2);
+        drop(guard);
+        assert!(requests.into_iter().all(|request| request.join().unwrap() == 200));
+
+        assert!(metric("santa_lock_slow_waits_total") >= slow_waits + 3.0);
+        assert!(metric("santa_lock_wait_seconds_total") >= waited + 3.0 * LOCK_WAIT_WARN.as_secs_f64());
+    }
+
+    #[test]
+    fn data_file_round_trip()
+    {
+        let mut data = DataBase::new();
+        let ids: Vec<Id> = new_user_ids(3).collect();
+        let group_id = new_group_id();
+        for (index, id) in ids.iter().enumerate()
+        {
+            data.users.insert(*id, format!("user {}", index));
+            let mut props = UserGroupProps::new(if index == 0 { Access::Admin } else { Access::User });
+            props.santa_id = Some(ids[(index + 1) % ids.len()]);
+            props.revealed = index == 1;
+            data.user_groups.insert(UserGroupId{user_id: *id, group_id}, props);
+        }
+        data.groups.insert(group_id, GroupProps{name: "Office".to_string(), is_closed: true, unique_names: true, is_private: true, budget: Some(500)});
+        data.user_tokens.insert("user token".to_string(), ids[0]);
+        data.reveal_tokens.insert("reveal token".to_string(), UserGroupId{user_id: ids[1], group_id});
+        data.guests.insert(ids[2], Instant::now());
+        data.digests.insert(group_id, "digest".to_string());
+        data.wishlists.insert(ids[1], vec!["book".to_string()]);
+        data.exclusions.insert(group_id, [exclusion_pair(ids[0], ids[2])].into_iter().collect());
+        data.invites.insert("invite".to_string(), Invite{group_id, single_use: true, expires_at: Some(1)});
+
+        let path = std::env::temp_dir().join(format!("santa-round-trip-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        data.save_to_file(path).unwrap();
+        let loaded = DataBase::load_from_file(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.users, data.users);
+        assert!(loaded.groups == data.groups);
+        assert!(loaded.user_groups == data.user_groups);
+        assert!(loaded.reveal_tokens == data.reveal_tokens);
+        assert_eq!(loaded.user_tokens, data.user_tokens);
+        assert_eq!(loaded.guests.keys().collect::<Vec<_>>(), vec![&ids[2]]);
+        assert_eq!(loaded.digests, data.digests);
+        assert_eq!(loaded.wishlists, data.wishlists);
+        assert_eq!(loaded.exclusions, data.exclusions);
+        assert!(loaded.invites == data.invites);
+        // Загрузка не откатывает счетчики, поэтому новые ID не совпадут с сохраненными
+        assert!(new_user_ids(1).start > ids[2]);
+        assert!(new_group_id() > group_id);
+    }
+
+    // Группа 1 из участников 0..santas.len(), участник i дарит santas[i]
+    fn assignment(santas: &[Option<Id>]) -> HashMap<UserGroupId, UserGroupProps>
+    {
+        santas.iter().enumerate()
+            .map(|(user_id, santa_id)|
+            {
+                let mut props = UserGroupProps::new(Access::User);
+                props.santa_id = *santa_id;
+                (UserGroupId{user_id: user_id as Id, group_id: 1}, props)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid_assignment_passes()
+    {
+        let mut user_groups = assignment(&[Some(1), Some(2), Some(0)]);
+        // Участники других групп не проверяются
+        user_groups.insert(UserGroupId{user_id: 0, group_id: 2}, UserGroupProps::new(Access::Admin));
+        assert_eq!(validate_assignment(1, &user_groups, &HashSet::new()), Ok(()));
+        assert_eq!(validate_assignment(3, &HashMap::new(), &HashSet::new()), Ok(()));
+    }
+
+    #[test]
+    fn broken_assignments_are_rejected()
+    {
+        let none = HashSet::new();
+        assert_eq!(validate_assignment(1, &assignment(&[Some(1), None, Some(0)]), &none), Err("user 1 has no recipient".to_string()));
+        assert_eq!(validate_assignment(1, &assignment(&[Some(1), Some(0), Some(2)]), &none), Err("user 2 gives to themselves".to_string()));
+        assert_eq!(validate_assignment(1, &assignment(&[Some(1), Some(7), Some(0)]), &none),
+            Err("user 1 gives to user 7, who is not in the group".to_string()));
+        // Не перестановка: 1 получает два подарка, 2 - ни одного
+        assert_eq!(validate_assignment(1, &assignment(&[Some(1), Some(0), Some(1)]), &none), Err("user 1 receives 2 gifts".to_string()));
+        assert_eq!(validate_assignment(1, &assignment(&[Some(2), Some(0), Some(1)]), &[exclusion_pair(1, 0)].into_iter().collect()),
+            Err("user 1 gives to user 0, but the pair is excluded".to_string()));
+    }
+
+    #[test]
+    fn json_routes_reach_their_handlers()
+    {
+        let app = new_app();
+        let routes = [(Method::Post, "/user/create"), (Method::Post, "/users/create_batch"), (Method::Post, "/group/create"),
+            (Method::Post, "/group/join"), (Method::Post, "/group/unadmin"), (Method::Delete, "/group/delete"),
+            (Method::Post, "/group/merge"), (Method::Post, "/group/make_admin"), (Method::Post, "/group/transfer_admin"),
+            (Method::Post, "/group/quit"), (Method::Post, "/group/set_budget"), (Method::Post, "/group/rename"),
+            (Method::Post, "/group/invite"), (Method::Post, "/group/set_private"), (Method::Post, "/group/set_unique_names"),
+            (Method::Post, "/group/exclude"), (Method::Post, "/group/kick"), (Method::Post, "/group/undo"),
+            (Method::Post, "/group/close"), (Method::Post, "/group/reopen"), (Method::Post, "/group/secret_santa"),
+            (Method::Post, "/group/reassign"), (Method::Post, "/group/remove_member_from_draw"), (Method::Post, "/group/reveal_token"), (Method::Post, "/group/assignments"),
+            (Method::Put, "/user/update"), (Method::Post, "/user/rename"), (Method::Post, "/user/wishlist"), (Method::Delete, "/user/delete")];
+        for (method, path) in routes
+        {
+            let (status, body) = send(&app, method, path, Some(json!({})));
+            assert_eq!(status, 400, "{}", path);
+            assert!(body["error"].as_str().unwrap().starts_with("missing field: "), "{}: {}", path, body);
+        }
+        assert_eq!(send(&app, Method::Post, "/group/import", Some(json!({}))).0, 422);
+        assert_eq!(send(&app, Method::Post, "/user/create", Some(json!([]))).0, 400);
+    }
+
+    #[test]
+    fn snapshot_after_quit_can_be_imported()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 4);
+        assert_eq!(post(&app, "/group/exclude", json!({"admin_id": users[0], "group_id": group_id, "user_a": users[1], "user_b": users[2]})).0, 200);
+        assert_eq!(post(&app, "/group/quit", json!({"user_id": users[2], "group_id": group_id})).0, 200);
+
+        let (status, snapshot) = get(&app, &format!("/group/snapshot?group_id={}&admin_id={}", group_id, users[0]));
+        assert_eq!(status, 200);
+        assert!(snapshot["exclusions"].as_array().is_none_or(|pairs| pairs.is_empty()));
+        let (status, body) = post(&app, "/group/import", snapshot);
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(body["users"].as_object().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn drawn_group_snapshot_keeps_assignments_on_import()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 4);
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        assert_eq!(post(&app, "/group/secret_santa", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        let path = format!("/group/snapshot?group_id={}&admin_id={}", group_id, users[0]);
+        let (_, hidden) = get(&app, &path);
+        assert_eq!(post(&app, "/group/import", hidden), (400, error_value("snapshot of a closed group has no assignments")));
+
+        let (_, snapshot) = get(&app, &format!("{}&spoil=true", path));
+        let (status, body) = post(&app, "/group/import", snapshot.clone());
+        assert_eq!(status, 200, "{}", body);
+        let new_id = |old_id: &Value| body["users"][old_id.to_string()].clone();
+        let imported_id = body["group_id"].as_u64().unwrap();
+        let (_, imported) = get(&app, &format!("/group/snapshot?group_id={}&admin_id={}&spoil=true", imported_id, new_id(&json!(users[0]))));
+        assert_eq!(imported["closed"], true);
+        let santas: HashMap<String, Value> = imported["members"].as_array().unwrap().iter()
+            .map(|member| (member["id"].to_string(), member["santa_id"].clone()))
+            .collect();
+        for member in snapshot["members"].as_array().unwrap()
+        {
+            assert_eq!(santas[&new_id(&member["id"]).to_string()], new_id(&member["santa_id"]));
+        }
+        let (_, validated) = get(&app, &format!("/group/validate?group_id={}&admin_id={}", imported_id, new_id(&json!(users[0]))));
+        assert_eq!(validated["valid"], true);
+    }
+
+    #[test]
+    fn sweep_removes_only_expired_guests_outside_groups()
+    {
+        let state = Arc::new(RwLock::new(DataBase::new()));
+        let app = build_app(state.clone());
+        let guest = |name: &str|
+        {
+            let (status, body) = post(&app, "/user/create", json!({"name": name, "guest": true}));
+            assert_eq!(status, 201);
+            body["id"].as_u64().unwrap() as Id
+        };
+        let (leaving_id, joined_id) = (guest("leaving guest"), guest("joined guest"));
+        let user_id = post(&app, "/user/create", json!({"name": "Ann"})).1["id"].as_u64().unwrap() as Id;
+        assert_eq!(post(&app, "/group/create", json!({"creator_id": user_id, "name": "group", "members": [joined_id]})).0, 201);
+        assert_eq!(get(&app, "/users").1["total"], 1);
+        assert_eq!(get(&app, "/users?guests=true").1["total"], 3);
+
+        // Пока срок не истек, сохранять нечего
+        let _env = set_env(&[]);
+        STATE_DIRTY.store(false, Ordering::Relaxed);
+        assert!(!sweep_guests(&state, Instant::now()));
+        assert!(!STATE_DIRTY.load(Ordering::Relaxed));
+
+        assert!(sweep_guests(&state, Instant::now() + guest_ttl()));
+        assert!(STATE_DIRTY.load(Ordering::Relaxed));
+        let guard = read_state(&state);
+        assert!(!guard.users.contains_key(&leaving_id));
+        assert!(!guard.user_tokens.values().any(|id| *id == leaving_id));
+        assert!(guard.users.contains_key(&joined_id) && guard.guests.contains_key(&joined_id));
+        assert!(guard.users.contains_key(&user_id));
+    }
+
+    #[test]
+    fn healthz_lists_broken_invariants()
+    {
+        let state = Arc::new(RwLock::new(DataBase::new()));
+        let app = build_app(state.clone());
+        let (group_id, users) = app_group(&app, 3);
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        assert_eq!(post(&app, "/group/secret_santa", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        assert_eq!(get(&app, "/healthz"), (200, json!({"status": "ok"})));
+
+        {
+            let mut guard = state.write().unwrap();
+            guard.user_groups.get_mut(&UserGroupId{user_id: users[1], group_id}).unwrap().santa_id = Some(users[1]);
+            guard.groups.insert(Id::MAX, GroupProps::new("empty".to_string()));
+            guard.user_groups.insert(UserGroupId{user_id: Id::MAX, group_id}, UserGroupProps::new(Access::User));
+        }
+        let (status, body) = get(&app, "/healthz");
+        assert_eq!(status, 503);
+        assert_eq!(body["status"], "unhealthy");
+        let failures: HashSet<&str> = body["failures"].as_array().unwrap().iter().map(|failure| failure.as_str().unwrap()).collect();
+        let expected = [
+            format!("membership of user {} in group {} references no user", Id::MAX, group_id),
+            format!("group {} has no valid assignment: user {} gives to themselves", group_id, users[1]),
+            format!("group {} assignment does not match its digest", group_id),
+            format!("group {} has no admin", Id::MAX),
+        ];
+        assert_eq!(failures, expected.iter().map(String::as_str).collect());
+    }
+
+    #[test]
+    fn api_key_is_required_for_writes()
+    {
+        let env = set_env(&[("SANTA_API_KEY", "secret key")]);
+        let app = new_app();
+        let create = |authorization: Option<&str>|
+        {
+            let mut request = new_request(Method::Post, "/user/create", Some(json!({"name": "Ann"})));
+            if let Some(authorization) = authorization
+            {
+                request.insert_header("Authorization", authorization);
+            }
+            let mut response = respond(&app, request);
+            (response.status() as u16, header(&response, "WWW-Authenticate"), response_json(&mut response))
+        };
+        let rejected = (401, "Bearer".to_string(), error_value("missing or wrong API key"));
+        assert_eq!(create(None), rejected);
+        assert_eq!(create(Some("Bearer wrong key")), rejected);
+        // Ключ без схемы Bearer не принимается
+        assert_eq!(create(Some("secret key")), rejected);
+        assert_eq!(create(Some("Bearer secret key")).0, 201);
+        // Чтение без SANTA_API_KEY_READS открыто
+        assert_eq!(send_with_env(&app, Method::Get, "/users", None).0, 200);
+        drop(env);
+
+        // Переменная без префикса SANTA_ ключ не включает
+        let _env = set_env(&[("API_KEY", "secret key")]);
+        assert_eq!(create(None).0, 201);
+    }
+
+    #[test]
+    fn bind_addr_is_read_from_santa_bind_addr()
+    {
+        let env = set_env(&[]);
+        assert_eq!(bind_addr().unwrap(), "127.0.0.1:8080".parse::<SocketAddr>().unwrap());
+        drop(env);
+        let env = set_env(&[("SANTA_BIND_ADDR", "0.0.0.0:9000"), ("BIND_ADDR", "0.0.0.0:9001")]);
+        assert_eq!(bind_addr().unwrap(), "0.0.0.0:9000".parse::<SocketAddr>().unwrap());
+        drop(env);
+        for invalid in ["localhost:8080", "0.0.0.0", "0.0.0.0:99999", ""]
+        {
+            let _env = set_env(&[("SANTA_BIND_ADDR", invalid)]);
+            let error = bind_addr().unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(error.to_string().starts_with("SANTA_BIND_ADDR must be an IP address with a port"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn arrays_longer_than_the_limit_are_rejected()
+    {
+        let _env = set_env(&[("SANTA_MAX_ARRAY_LEN", "3")]);
+        let app = new_app();
+        let users: Vec<Value> = (0..4)
+            .map(|index| send_with_env(&app, Method::Post, "/user/create", Some(json!({"name": format!("user {}", index)}))).1["id"].clone())
+            .collect();
+        let create = |members: &[Value]| send_with_env(&app, Method::Post, "/group/create", Some(json!({"creator_id": users[0], "name": "group", "members": members})));
+        assert_eq!(create(&users[1..4]).0, 201);
+        assert_eq!(create(&users[0..4]), (400, error_value("field `members` has more than 3 items")));
+
+        // Вложенный массив проверяется так же, в том числе в маршрутах без списков в обработчике
+        let member = json!({"id": 0, "name": "Ann", "admin": true, "wishlist": ["a", "b", "c", "d"]});
+        assert_eq!(send_with_env(&app, Method::Post, "/group/import", Some(json!({"name": "group", "members": [member]}))),
+            (400, error_value("field `members[0].wishlist` has more than 3 items")));
+        assert_eq!(send_with_env(&app, Method::Put, "/user/update", Some(json!({"user_id": users[0], "name": "Ann", "extra": [[1, 2, 3, 4]]}))),
+            (400, error_value("field `extra[0]` has more than 3 items")));
+    }
+
+    #[test]
+    fn bodies_over_the_size_limit_are_rejected()
+    {
+        let _env = set_env(&[("SANTA_MAX_BODY_BYTES", "64")]);
+        let app = new_app();
+        // Тело ровно из len байт; без длины - как при Transfer-Encoding: chunked
+        let create = |len: usize, known_len: bool|
+        {
+            let body = format!(r#"{{"name":"{}"}}"#, "a".repeat(len - r#"{"name":""}"#.len()));
+            assert_eq!(body.len(), len);
+            let mut request = new_request(Method::Post, "/user/create", None);
+            request.set_body(if known_len
+            {
+                tide::Body::from_string(body)
+            }
+            else
+            {
+                tide::Body::from_reader(futures::io::Cursor::new(body.into_bytes()), None)
+            });
+            let mut response = respond(&app, request);
+            (response.status() as u16, response_json(&mut response))
+        };
+        let too_large = (413, error_value("request body is larger than 64 bytes"));
+        for known_len in [true, false]
+        {
+            assert_eq!(create(64, known_len).0, 201, "known length: {}", known_len);
+            assert_eq!(create(65, known_len), too_large, "known length: {}", known_len);
+        }
+        assert_eq!(create(1000, true), too_large);
+    }
+
+    #[test]
+    fn min_group_size_is_checked_at_the_boundary()
+    {
+        let app = new_app();
+        let (pair_id, pair) = app_group(&app, 2);
+        let (trio_id, trio) = app_group(&app, 3);
+        let (single_id, single) = app_group(&app, 1);
+        let close = |group_id: Id, admin_id: Id| send_with_env(&app, Method::Post, "/group/close", Some(json!({"admin_id": admin_id, "group_id": group_id})));
+        let too_small = |members: usize, min_members: usize| (400, json!({
+            "error": format!("group has {} members, at least {} are needed", members, min_members),
+            "members": members,
+            "min_members": min_members,
+        }));
+
+        let env = set_env(&[("SANTA_MIN_GROUP_SIZE", "3")]);
+        assert_eq!(close(pair_id, pair[0]), too_small(2, 3));
+        assert_eq!(close(trio_id, trio[0]).0, 200);
+        drop(env);
+
+        // Минимум подняли после закрытия: жеребьевка проверяет его снова
+        let env = set_env(&[("SANTA_MIN_GROUP_SIZE", "4")]);
+        assert_eq!(send_with_env(&app, Method::Post, "/group/secret_santa", Some(json!({"admin_id": trio[0], "group_id": trio_id}))), too_small(3, 4));
+        drop(env);
+
+        // Меньше двух задать нельзя
+        let _env = set_env(&[("SANTA_MIN_GROUP_SIZE", "1")]);
+        assert_eq!(close(single_id, single[0]), too_small(1, 2));
+        assert_eq!(close(pair_id, pair[0]).0, 200);
+    }
+
+    #[test]
+    fn wait_closed_returns_when_the_draw_runs()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 3);
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        assert_eq!(get(&app, &format!("/group/wait_closed?group_id={}", Id::MAX)), (404, error_value("no such group")));
+
+        // ENV_LOCK берет сам тест: ожидающий запрос не должен держать его, пока идет жеребьевка
+        let _env = default_env();
+        let waiter =
+        {
+            let app = app.clone();
+            std::thread::spawn(move ||
+            {
+                let mut response = respond(&app, new_request(Method::Get, &format!("/group/wait_closed?group_id={}", group_id), None));
+                (response.status() as u16, response_json(&mut response))
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!waiter.is_finished());
+        assert_eq!(send_with_env(&app, Method::Post, "/group/secret_santa", Some(json!({"admin_id": users[0], "group_id": group_id}))).0, 200);
+        assert_eq!(waiter.join().unwrap(), (200, json!({"closed": true})));
+    }
+
+    #[test]
+    fn camel_case_applies_to_every_response()
+    {
+        let _env = set_env(&[("SANTA_JSON_CASE", "camel")]);
+        let app = new_app();
+        let post = |path: &str, body: Value| send_with_env(&app, Method::Post, path, Some(body));
+        let get = |path: &str| send_with_env(&app, Method::Get, path, None);
+        let users: Vec<Value> = ["Ann", "Bob"].iter().map(|name| post("/user/create", json!({"name": name})).1["id"].clone()).collect();
+        let (status, body) = post("/group/create", json!({"creator_id": users[0], "name": "group", "members": [users[1]]}));
+        assert_eq!(status, 201);
+        let group_id = body["groupId"].clone();
+        assert!(body.get("group_id").is_none());
+
+        // Вложенные объекты тоже
+        let (_, user) = get(&format!("/user/{}", users[0]));
+        assert_eq!(user["groups"], json!([{"groupId": group_id, "name": "group", "accessLevel": "admin", "isClosed": false}]));
+
+        assert_eq!(post("/group/close", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        assert_eq!(post("/group/secret_santa", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        let (_, target) = get(&format!("/group/target_by_id/{}/{}", users[0], group_id));
+        assert_eq!(target, json!({"cyshForId": users[1], "cyshForName": "Bob", "cyshForWishlist": [], "budget": null}));
+        assert_eq!(get(&format!("/group/target_by_id/{}/{}", users[0], Id::MAX)), (404, error_value("no such group")));
+
+        // Снимок в camelCase принимается обратно
+        let (_, snapshot) = get(&format!("/group/snapshot?group_id={}&admin_id={}&spoil=true", group_id, users[0]));
+        assert_eq!(snapshot["groupId"], group_id);
+        assert_eq!(snapshot["uniqueNames"], false);
+        assert!(snapshot["members"].as_array().unwrap().iter().all(|member| member.get("santaId").is_some()));
+        assert_eq!(post("/group/import", snapshot).0, 200);
+    }
+
+    #[test]
+    fn reveal_requests_are_limited_per_ip_and_group()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 2);
+        let path = format!("/group/target_by_id/{}/{}", users[0], group_id);
+        let reveal = |peer_addr: &str|
+        {
+            let _env = default_env();
+            let mut request = new_request(Method::Get, &path, None);
+            request.set_peer_addr(Some(peer_addr));
+            let mut response = respond(&app, request);
+            (response.status() as u16, header(&response, "Retry-After"), response_json(&mut response))
+        };
+        for _ in 0..REVEAL_LIMIT
+        {
+            assert_eq!(reveal("1.2.3.4:5").0, 400);
+        }
+        let (status, retry_after, body) = reveal("1.2.3.4:6");
+        assert_eq!((status, body), (429, error_value("too many reveal requests, try again later")));
+        let retry_after: u64 = retry_after.parse().unwrap();
+        assert!((1..=REVEAL_WINDOW.as_secs()).contains(&retry_after), "{}", retry_after);
+        // Другой IP считается отдельно
+        assert_eq!(reveal("1.2.3.5:5").0, 400);
+    }
+
+    #[test]
+    fn join_accepts_string_and_numeric_ids()
+    {
+        let app = new_app();
+        let (group_id, _) = app_group(&app, 1);
+        let user = |name: &str| post(&app, "/user/create", json!({"name": name})).1["id"].as_u64().unwrap() as Id;
+        let (numeric_id, string_id) = (user("numeric"), user("string"));
+        assert_eq!(post(&app, "/group/join", json!({"user_id": numeric_id, "group_id": group_id})).0, 200);
+        assert_eq!(post(&app, "/group/join", json!({"user_id": string_id.to_string(), "group_id": group_id.to_string()})).0, 200);
+        let (_, members) = get(&app, &format!("/group/members?group_id={}", group_id));
+        let member_ids: Vec<u64> = members["members"].as_array().unwrap().iter().map(|member| member["user_id"].as_u64().unwrap()).collect();
+        assert!(member_ids.contains(&(numeric_id as u64)) && member_ids.contains(&(string_id as u64)), "{}", members);
+
+        for (user_id, message) in [
+            (json!("12a"), "field user_id must be a number"),
+            (json!(-1), "field user_id must be a number"),
+            (json!(1.5), "field user_id must be a number"),
+            (json!(4294967296u64), "field user_id must be a number"),
+            (json!(true), "field user_id must be a number"),
+            (json!(null), "field user_id must be a number"),
+        ]
+        {
+            assert_eq!(post(&app, "/group/join", json!({"user_id": user_id, "group_id": group_id})), (400, error_value(message)), "{}", user_id);
+        }
+        assert_eq!(post(&app, "/group/join", json!({"group_id": group_id})), (400, error_value("missing field: user_id")));
+    }
+
+    #[test]
+    fn deleted_ids_are_not_reused()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 2);
+        let deleted_id = users[1];
+        assert_eq!(send(&app, Method::Delete, "/user/delete", Some(json!({"user_id": deleted_id}))).0, 200);
+        let (status, body) = post(&app, "/user/create", json!({"name": "user 1"}));
+        assert_eq!(status, 201);
+        let new_id = body["id"].as_u64().unwrap() as Id;
+        assert!(new_id > deleted_id);
+        assert_eq!(get(&app, &format!("/user/{}", deleted_id)), (404, error_value("no such user")));
+        assert_eq!(get(&app, &format!("/user/{}", new_id)).1["groups"], json!([]));
+        let (_, members) = get(&app, &format!("/group/members?group_id={}", group_id));
+        assert_eq!(members["members"].as_array().unwrap().len(), 1);
+
+        assert_eq!(send(&app, Method::Delete, "/group/delete", Some(json!({"admin_id": users[0], "group_id": group_id}))).0, 200);
+        let (_, body) = post(&app, "/group/create", json!({"creator_id": new_id, "name": "group"}));
+        assert!(body["group_id"].as_u64().unwrap() as Id > group_id);
+        assert_eq!(get(&app, &format!("/group/members?group_id={}", group_id)), (404, error_value("no such group")));
+    }
+
+    #[test]
+    fn reopen_warns_only_about_revealed_assignments()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 3);
+        let admin = json!({"admin_id": users[0], "group_id": group_id});
+        assert_eq!(post(&app, "/group/reopen", admin.clone()), (400, error_value("group is not closed")));
+
+        // Закрыта, но жеребьевки не было
+        assert_eq!(post(&app, "/group/close", admin.clone()).0, 200);
+        assert_eq!(post(&app, "/group/reopen", admin.clone()), (200, json!({"warning": false, "revealed": []})));
+
+        // Жеребьевка была, но никто не смотрел
+        assert_eq!(post(&app, "/group/close", admin.clone()).0, 200);
+        assert_eq!(post(&app, "/group/secret_santa", admin.clone()).0, 200);
+        assert_eq!(post(&app, "/group/reopen", admin.clone()), (200, json!({"warning": false, "revealed": []})));
+
+        assert_eq!(post(&app, "/group/close", admin.clone()).0, 200);
+        assert_eq!(post(&app, "/group/secret_santa", admin.clone()).0, 200);
+        assert_eq!(get(&app, &format!("/group/target_by_id/{}/{}", users[2], group_id)).0, 200);
+        let (_, body) = post(&app, "/group/reveal_token", json!({"admin_id": users[0], "group_id": group_id, "user_id": users[1]}));
+        let reveal = format!("/reveal?token={}", body["token"].as_str().unwrap());
+        assert_eq!(get(&app, &reveal).0, 200);
+        let (_, body) = post(&app, "/group/reveal_token", json!({"admin_id": users[0], "group_id": group_id, "user_id": users[0]}));
+        let unused = format!("/reveal?token={}", body["token"].as_str().unwrap());
+        assert_eq!(post(&app, "/group/reopen", admin.clone()), (200, json!({"warning": true, "revealed": [users[1], users[2]]})));
+
+        // Назначения, хеш и ссылки сброшены
+        assert_eq!(get(&app, &format!("/group/target_by_id/{}/{}", users[2], group_id)), (400, error_value("secret santa has not started yet")));
+        assert_eq!(get(&app, &unused), (404, error_value("no such token")));
+        let (_, snapshot) = get(&app, &format!("/group/snapshot?group_id={}&admin_id={}", group_id, users[0]));
+        assert_eq!((&snapshot["closed"], snapshot.get("digest")), (&json!(false), None));
+        // Следующая жеребьевка начинается без отметок
+        assert_eq!(post(&app, "/group/close", admin.clone()).0, 200);
+        assert_eq!(post(&app, "/group/reopen", admin), (200, json!({"warning": false, "revealed": []})));
+    }
+
+    #[test]
+    fn rename_is_visible_in_users()
+    {
+        let app = new_app();
+        let user_id = post(&app, "/user/create", json!({"name": "Ann"})).1["id"].clone();
+        assert_eq!(post(&app, "/user/rename", json!({"user_id": user_id, "name": "  Anna   Maria "})).0, 200);
+        let (_, users) = get(&app, "/users");
+        assert_eq!(users["items"][user_id.to_string()], "Anna Maria");
+        assert_eq!(post(&app, "/user/rename", json!({"user_id": user_id, "name": " "})), (400, error_value("bad name")));
+        assert_eq!(post(&app, "/user/rename", json!({"user_id": Id::MAX, "name": "Bob"})), (404, error_value("No such id")));
+        assert_eq!(get(&app, "/users").1["items"][user_id.to_string()], "Anna Maria");
+    }
+
+    #[test]
+    fn user_delete_lists_last_admin_groups()
+    {
+        let app = new_app();
+        let (first_id, users) = app_group(&app, 2);
+        let (admin_id, member_id) = (users[0], users[1]);
+        let group = |creator_id: Id| post(&app, "/group/create", json!({"creator_id": creator_id, "name": "group", "members": [member_id]})).1["group_id"].as_u64().unwrap() as Id;
+        let second_id = group(admin_id);
+        let delete = |user_id: Id| send(&app, Method::Delete, "/user/delete", Some(json!({"user_id": user_id})));
+
+        assert_eq!(delete(admin_id), (400, json!({
+            "error": format!("User cannot be deleted from groups {}, {} because he is the last admin there.", first_id, second_id),
+            "groups": [first_id, second_id],
+        })));
+
+        // С закрытой группой пользователь остается, groups - только открытые
+        assert_eq!(post(&app, "/group/make_admin", json!({"admin_id": admin_id, "group_id": first_id, "member_id": member_id})).0, 200);
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": member_id, "group_id": first_id})).0, 200);
+        assert_eq!(delete(admin_id), (400, json!({
+            "error": format!("User has closed groups. So he was deleted from opened groups, if he wasn't last admin. User cannot be deleted from group {} because of last admin.", second_id),
+            "groups": [second_id],
+        })));
+
+        let lonely_id = post(&app, "/user/create", json!({"name": "lonely"})).1["id"].as_u64().unwrap() as Id;
+        let third_id = group(lonely_id);
+        assert_eq!(delete(lonely_id), (400, json!({
+            "error": format!("User cannot be deleted from group {} because he is the last admin there.", third_id),
+            "groups": [third_id],
+        })));
+    }
+
+    #[test]
+    fn pages_at_the_edges_of_the_list()
+    {
+        let app = new_app();
+        let (_, users) = app_group(&app, 5);
+        let page = |query: &str|
+        {
+            let (status, body) = get(&app, &format!("/users?{}", query));
+            assert_eq!(status, 200, "{}", query);
+            // Ключи объекта упорядочены как строки
+            let mut ids: Vec<Id> = body["items"].as_object().unwrap().keys().map(|id| id.parse().unwrap()).collect();
+            ids.sort_unstable();
+            (ids, body["total"].clone(), body["offset"].clone(), body["limit"].clone())
+        };
+        assert_eq!(page("offset=0&limit=2"), (users[0..2].to_vec(), json!(5), json!(0), json!(2)));
+        // Последняя страница неполная
+        assert_eq!(page("offset=4&limit=2"), (users[4..].to_vec(), json!(5), json!(4), json!(2)));
+        assert_eq!(page("offset=5&limit=2"), (vec![], json!(5), json!(5), json!(2)));
+        assert_eq!(page("offset=100"), (vec![], json!(5), json!(100), json!(PAGE_DEFAULT_LIMIT)));
+        assert_eq!(page("limit=0"), (vec![], json!(5), json!(0), json!(0)));
+        assert_eq!(page("limit=1000000"), (users.clone(), json!(5), json!(0), json!(PAGE_MAX_LIMIT)));
+        assert_eq!(get(&app, "/users?offset=-1").0, 400);
+
+        let (_, groups) = get(&app, "/groups?offset=1");
+        assert_eq!((groups["items"].as_object().unwrap().len(), &groups["total"]), (0, &json!(1)));
+    }
+
+    #[test]
+    fn reassign_keeps_the_min_group_size()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 4);
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        assert_eq!(post(&app, "/group/secret_santa", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        let _env = set_env(&[("SANTA_MIN_GROUP_SIZE", "3")]);
+        let reassign = |leaving_id: Id| send_with_env(&app, Method::Post, "/group/reassign", Some(json!({"admin_id": users[0], "group_id": group_id, "leaving_id": leaving_id})));
+        assert_eq!(reassign(users[3]).0, 200);
+        assert_eq!(reassign(users[2]), (400, json!({"error": "group has 2 members, at least 3 are needed", "members": 2, "min_members": 3})));
+        let (_, validated) = send_with_env(&app, Method::Get, &format!("/group/validate?group_id={}&admin_id={}", group_id, users[0]), None);
+        assert_eq!(validated["valid"], true);
+    }
+
+    #[test]
+    fn state_is_saved_on_sigint_and_sigterm()
+    {
+        let path = std::env::temp_dir().join(format!("santa-shutdown-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let env = set_env(&[("SANTA_DATA_FILE", path)]);
+        let state = Arc::new(RwLock::new(DataBase::new()));
+        let mut signals = Signals::new([SIGINT, SIGTERM]).unwrap();
+        for (signal, name) in [(SIGINT, "Ann"), (SIGTERM, "Bob")]
+        {
+            let user_id = new_user_id();
+            state.write().unwrap().users.insert(user_id, name.to_string());
+            signal_hook::low_level::raise(signal).unwrap();
+            let (code, guard) = save_on_shutdown(&mut signals, &state);
+            assert_eq!(code, 0);
+            drop(guard);
+            let loaded = DataBase::load_from_file(path).unwrap();
+            assert_eq!(loaded.users.get(&user_id).map(String::as_str), Some(name), "signal {}", signal);
+        }
+        std::fs::remove_file(path).unwrap();
+        drop(env);
+
+        // Файл, который нельзя записать, дает код выхода 1
+        let _env = set_env(&[("SANTA_DATA_FILE", "/nonexistent/santa.json")]);
+        signal_hook::low_level::raise(SIGTERM).unwrap();
+        assert_eq!(save_on_shutdown(&mut signals, &state).0, 1);
+    }
+
+    #[test]
+    fn names_are_collapsed_and_limited()
+    {
+        assert_eq!(normalize_name(" \t Anna \n\u{a0} Maria  "), Ok("Anna Maria".to_string()));
+        assert_eq!(normalize_name(" \t\n"), Err("bad name".to_string()));
+        let too_long = Err(format!("name must be at most {} characters long", NAME_MAX_LEN));
+        // Длина считается в символах и после схлопывания пробелов
+        assert_eq!(normalize_name(&"я".repeat(NAME_MAX_LEN)), Ok("я".repeat(NAME_MAX_LEN)));
+        assert_eq!(normalize_name(&"я".repeat(NAME_MAX_LEN + 1)), too_long);
+        assert_eq!(normalize_name(&format!("  {}{}b  ", "a".repeat(NAME_MAX_LEN - 2), " ".repeat(50))), Ok(format!("{} b", "a".repeat(NAME_MAX_LEN - 2))));
+        assert_eq!(normalize_name(&format!("{} {}", "a".repeat(50), "b".repeat(50))), too_long);
+
+        let app = new_app();
+        let (status, body) = post(&app, "/user/create", json!({"name": "  Ann \t  Lee "}));
+        assert_eq!(status, 201);
+        assert_eq!(get(&app, &format!("/user/{}", body["id"])).1["name"], "Ann Lee");
+        assert_eq!(post(&app, "/user/create", json!({"name": "x".repeat(NAME_MAX_LEN + 1)})), (400, error_value("name must be at most 100 characters long")));
+        assert_eq!(post(&app, "/group/create", json!({"creator_id": body["id"], "name": "x".repeat(NAME_MAX_LEN + 1)})),
+            (400, error_value("name must be at most 100 characters long")));
+    }
+
+    #[test]
+    fn group_is_created_with_its_members()
+    {
+        let app = new_app();
+        let users: Vec<Id> = ["Ann", "Bob", "Eve"].iter()
+            .map(|name| post(&app, "/user/create", json!({"name": name})).1["id"].as_u64().unwrap() as Id)
+            .collect();
+        let (status, body) = post(&app, "/group/create", json!({"creator_id": users[0], "name": "Office", "members": [users[1], users[2].to_string()]}));
+        assert_eq!(status, 201);
+        let group_id = body["group_id"].clone();
+        let (_, members) = get(&app, &format!("/group/members?group_id={}", group_id));
+        assert_eq!(members, json!({"name": "Office", "members": [
+            {"user_id": users[0], "name": "Ann", "access_level": "admin"},
+            {"user_id": users[1], "name": "Bob", "access_level": "user"},
+            {"user_id": users[2], "name": "Eve", "access_level": "user"},
+        ]}));
+
+        // С неизвестным участником группа не создается
+        assert_eq!(post(&app, "/group/create", json!({"creator_id": users[0], "name": "Broken", "members": [users[1], Id::MAX]})),
+            (404, error_value("no such user")));
+        assert_eq!(get(&app, "/groups").1["total"], 1);
+    }
+
+    #[test]
+    fn user_zero_has_no_assignment_before_the_draw()
+    {
+        // Пользователь с ID 0 создается только первым, поэтому состояние собирается вручную
+        let mut data = DataBase::new();
+        let group_id = new_group_id();
+        let other_id = new_user_id().max(1);
+        data.users.insert(0, "Zero".to_string());
+        data.users.insert(other_id, "Other".to_string());
+        data.groups.insert(group_id, GroupProps::new("group".to_string()));
+        data.user_groups.insert(UserGroupId{user_id: 0, group_id}, UserGroupProps::new(Access::Admin));
+        data.user_groups.insert(UserGroupId{user_id: other_id, group_id}, UserGroupProps::new(Access::User));
+        let app = build_app(Arc::new(RwLock::new(data)));
+
+        let not_started = (400, error_value("secret santa has not started yet"));
+        assert_eq!(get(&app, &format!("/group/target_by_id/0/{}", group_id)), not_started);
+        assert_eq!(get(&app, &format!("/group/target_by_id/{}/{}", other_id, group_id)), not_started);
+        assert_eq!(get(&app, &format!("/group/giver?group_id={}&receiver_id=0&admin_id=0", group_id)), not_started);
+        let (_, snapshot) = get(&app, &format!("/group/snapshot?group_id={}&admin_id=0&spoil=true", group_id));
+        assert!(snapshot["members"].as_array().unwrap().iter().all(|member| member.get("santa_id").is_none()), "{}", snapshot);
+
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": 0, "group_id": group_id})).0, 200);
+        assert_eq!(post(&app, "/group/secret_santa", json!({"admin_id": 0, "group_id": group_id})).0, 200);
+        assert_eq!(get(&app, &format!("/group/target_by_id/0/{}", group_id)).1["cysh_for_id"], other_id);
+        assert_eq!(get(&app, &format!("/group/target_by_id/{}/{}", other_id, group_id)).1["cysh_for_id"], 0);
+    }
+
+    #[test]
+    fn giver_matches_target_by_id()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 4);
+        let admin_id = users[0];
+        let outsider_id = post(&app, "/user/create", json!({"name": "outsider"})).1["id"].as_u64().unwrap() as Id;
+        let giver_path = |receiver_id: Id, admin_id: Id| format!("/group/giver?group_id={}&receiver_id={}&admin_id={}", group_id, receiver_id, admin_id);
+
+        assert_eq!(get(&app, &giver_path(users[1], admin_id)), (400, error_value("secret santa has not started yet")));
+
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": admin_id, "group_id": group_id})).0, 200);
+        assert_eq!(post(&app, "/group/secret_santa", json!({"admin_id": admin_id, "group_id": group_id})).0, 200);
+        for &giver_id in &users
+        {
+            let (status, target) = get(&app, &format!("/group/target_by_id/{}/{}", giver_id, group_id));
+            assert_eq!(status, 200);
+            let receiver_id = target["cysh_for_id"].as_u64().unwrap() as Id;
+            assert_eq!(get(&app, &giver_path(receiver_id, admin_id)), (200, json!({"giver_id": giver_id})));
+        }
+
+        assert_eq!(get(&app, &giver_path(outsider_id, admin_id)), (404, error_value("nobody gives a gift to this user")));
+        assert_eq!(get(&app, &giver_path(users[2], users[1])), (400, error_value("This user is not an admin.")));
+        assert_eq!(get(&app, &giver_path(users[2], outsider_id)), (400, error_value("user does not belong to this group")));
+    }
+
+    #[test]
+    fn graph_has_a_node_and_an_edge_per_member()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 5);
+        let admin_id = users[0];
+        let graph_path = |admin_id: Id| format!("/group/graph?group_id={}&admin_id={}", group_id, admin_id);
+
+        assert_eq!(get(&app, &graph_path(admin_id)), (400, error_value("secret santa has not started yet")));
+
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": admin_id, "group_id": group_id})).0, 200);
+        assert_eq!(post(&app, "/group/secret_santa", json!({"admin_id": admin_id, "group_id": group_id})).0, 200);
+        let (status, graph) = get(&app, &graph_path(admin_id));
+        assert_eq!(status, 200);
+
+        let nodes = graph["nodes"].as_array().unwrap();
+        assert_eq!(nodes.len(), users.len());
+        for (node, (index, user_id)) in nodes.iter().zip(users.iter().enumerate())
+        {
+            assert_eq!(node, &json!({"id": user_id, "name": format!("user {}", index)}));
+        }
+
+        let edges = graph["edges"].as_array().unwrap();
+        assert_eq!(edges.len(), users.len());
+        for edge in edges
+        {
+            let (status, target) = get(&app, &format!("/group/target_by_id/{}/{}", edge["from"], group_id));
+            assert_eq!(status, 200);
+            assert_eq!(target["cysh_for_id"], edge["to"]);
+        }
+
+        assert_eq!(get(&app, &graph_path(users[1])), (400, error_value("This user is not an admin.")));
+        assert_eq!(get(&app, &format!("/group/graph?group_id={}&admin_id={}", Id::MAX, admin_id)), (404, error_value("no such group")));
+    }
+
+    #[test]
+    fn users_are_filtered_by_name_and_admin_role()
+    {
+        let app = new_app();
+        let create = |name: &str| post(&app, "/user/create", json!({"name": name})).1["id"].as_u64().unwrap() as Id;
+        let alice_id = create("Alice");
+        let alina_id = create("Alina");
+        let bob_id = create("Bob");
+        assert_eq!(post(&app, "/group/create", json!({"creator_id": alice_id, "name": "group", "members": [bob_id]})).0, 201);
+
+        let users = |query: &str| -> Vec<Id>
+        {
+            let (status, body) = get(&app, &format!("/users?{}", query));
+            assert_eq!(status, 200);
+            let items = body["items"].as_object().unwrap();
+            assert_eq!(body["total"], items.len());
+            // Ключи объекта упорядочены как строки
+            let mut ids: Vec<Id> = items.keys().map(|id| id.parse().unwrap()).collect();
+            ids.sort_unstable();
+            ids
+        };
+        assert_eq!(users(""), vec![alice_id, alina_id, bob_id]);
+        assert_eq!(users("q=Ali"), vec![alice_id, alina_id]);
+        assert_eq!(users("q=ali"), Vec::<Id>::new());
+        assert_eq!(users("admin_somewhere=true"), vec![alice_id]);
+        assert_eq!(users("admin_somewhere=false"), vec![alina_id, bob_id]);
+        assert_eq!(users("q=Ali&admin_somewhere=false"), vec![alina_id]);
+        assert_eq!(users("q=Bob&admin_somewhere=true"), Vec::<Id>::new());
+    }
+
+    #[test]
+    fn server_rate_limit_answers_503_with_retry_after()
+    {
+        let app = new_app();
+        let env = set_env(&[("SANTA_MAX_RPS", "1")]);
+        // Один запрос в секунду без всплеска: самое позднее второй запрос подряд упирается в лимит
+        let rejected = (0..3)
+            .map(|_| respond(&app, new_request(Method::Get, "/health", None)))
+            .find(|response| response.status() as u16 == 503);
+        let mut response = rejected.expect("requests over SANTA_MAX_RPS must be rejected");
+        assert_eq!(header(&response, "Retry-After"), "1");
+        assert_eq!(response_json(&mut response), error_value("server is overloaded, try again later"));
+        drop(env);
+
+        assert_eq!(get(&app, "/health").0, 200);
+    }
+
+    #[test]
+    fn user_is_shown_with_its_groups()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 2);
+        let loner_id = post(&app, "/user/create", json!({"name": "loner"})).1["id"].as_u64().unwrap() as Id;
+
+        assert_eq!(get(&app, &format!("/user/{}", users[1])), (200, json!({
+            "id": users[1],
+            "name": "user 1",
+            "groups": [{"group_id": group_id, "name": "group", "access_level": "user", "is_closed": false}],
+        })));
+        assert_eq!(get(&app, &format!("/user/{}", users[0])).1["groups"][0]["access_level"], "admin");
+        assert_eq!(get(&app, &format!("/user/{}", loner_id)), (200, json!({"id": loner_id, "name": "loner", "groups": []})));
+
+        assert_eq!(get(&app, &format!("/user/{}", Id::MAX)), (404, error_value("no such user")));
+        assert_eq!(get(&app, "/user/abc"), (400, error_value("Wrong format user id")));
+    }
+
+    #[test]
+    fn contains_reports_membership_and_access_level()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 2);
+        let outsider_id = post(&app, "/user/create", json!({"name": "outsider"})).1["id"].as_u64().unwrap() as Id;
+        let contains = |user_id: Id, group_id: Id| get(&app, &format!("/group/contains?group_id={}&user_id={}", group_id, user_id));
+
+        assert_eq!(contains(users[0], group_id), (200, json!({"member": true, "access_level": "admin"})));
+        assert_eq!(contains(users[1], group_id), (200, json!({"member": true, "access_level": "user"})));
+        assert_eq!(contains(outsider_id, group_id), (200, json!({"member": false, "access_level": null})));
+        assert_eq!(contains(users[0], Id::MAX), (404, error_value("no such group")));
+    }
+
+    // Предварительный запрос браузера перед POST с заголовком X-User-Token
+    fn preflight(app: &tide::Server<Arc<RwLock<DataBase>>>, headers: &str) -> tide::http::Response
+    {
+        let mut request = new_request(Method::Options, "/group/join", None);
+        request.insert_header("Origin", "https://santa.example");
+        request.insert_header("Access-Control-Request-Method", "POST");
+        request.insert_header("Access-Control-Request-Headers", headers);
+        respond(app, request)
+    }
+
+    fn header(response: &tide::http::Response, name: &str) -> String
+    {
+        response.header(name).map(|values| values.last().as_str().to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn preflight_allows_user_token()
+    {
+        let _env = set_env(&[("SANTA_CORS_ORIGINS", "https://santa.example")]);
+        let app = new_app();
+        let response = preflight(&app, "content-type, x-user-token, idempotency-key");
+        assert!(response.status().is_success());
+        let allowed: Vec<String> = header(&response, "Access-Control-Allow-Headers").split(',').map(|name| name.trim().to_lowercase()).collect();
+        assert!(allowed.contains(&"x-user-token".to_string()), "{:?}", allowed);
+        assert!(allowed.contains(&"authorization".to_string()), "{:?}", allowed);
+        assert!(allowed.contains(&"idempotency-key".to_string()), "{:?}", allowed);
+    }
+
+    #[test]
+    fn preflight_allows_configured_origins_and_methods()
+    {
+        let env = set_env(&[("SANTA_CORS_ORIGINS", "https://other.example, https://santa.example")]);
+        let app = new_app();
+        let response = preflight(&app, "content-type");
+        assert_eq!(response.status() as u16, 200);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), "https://santa.example");
+        let methods: Vec<String> = header(&response, "Access-Control-Allow-Methods").split(',').map(|method| method.trim().to_string()).collect();
+        for method in ["GET", "POST", "PUT", "DELETE", "OPTIONS"]
+        {
+            assert!(methods.iter().any(|allowed| allowed == method), "{} in {:?}", method, methods);
+        }
+
+        let mut request = new_request(Method::Options, "/group/join", None);
+        request.insert_header("Origin", "https://evil.example");
+        request.insert_header("Access-Control-Request-Method", "POST");
+        let response = respond(&app, request);
+        assert_eq!(response.status() as u16, 401);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), "");
+        drop(env);
+
+        // При `*` источник запроса возвращается как есть
+        let _env = set_env(&[("SANTA_CORS_ORIGINS", "*")]);
+        let app = new_app();
+        assert_eq!(header(&preflight(&app, "content-type"), "Access-Control-Allow-Origin"), "https://santa.example");
+    }
+
+    #[test]
+    fn repeated_idempotency_key_replays_the_first_response()
+    {
+        let app = new_app();
+        let create = ||
+        {
+            let _env = default_env();
+            let mut request = new_request(Method::Post, "/user/create", Some(json!({"name": "Ann"})));
+            request.insert_header("Idempotency-Key", "replay-test-key");
+            respond(&app, request)
+        };
+        let mut first = create();
+        let mut second = create();
+        assert_eq!((first.status() as u16, second.status() as u16), (201, 201));
+        assert_eq!(header(&second, "Idempotent-Replayed"), "true");
+        assert_eq!(header(&second, "Location"), header(&first, "Location"));
+        assert_eq!(response_json(&mut second), response_json(&mut first));
+        // Второй пользователь не создан
+        assert_eq!(get(&app, "/users").1["total"], 1);
+    }
+
+    #[test]
+    fn created_objects_are_located_across_origins()
+    {
+        let _env = set_env(&[("SANTA_CORS_ORIGINS", "https://santa.example")]);
+        let app = new_app();
+        let mut request = new_request(Method::Post, "/user/create", Some(json!({"name": "Ann"})));
+        request.insert_header("Origin", "https://santa.example");
+        let mut response = respond(&app, request);
+        assert_eq!(response.status() as u16, 201);
+        let user_id = response_json(&mut response)["id"].as_u64().unwrap();
+        assert_eq!(header(&response, "Location"), format!("/user/{}", user_id));
+        assert!(header(&response, "Access-Control-Expose-Headers").split(',').any(|name| name.trim().eq_ignore_ascii_case("location")));
+
+        let mut response = respond(&app, new_request(Method::Post, "/group/create", Some(json!({"creator_id": user_id, "name": "Office"}))));
+        assert_eq!(response.status() as u16, 201);
+        let group_id = response_json(&mut response)["group_id"].as_u64().unwrap();
+        assert_eq!(header(&response, "Location"), format!("/group/members?group_id={}", group_id));
+        let mut members = respond(&app, new_request(Method::Get, &header(&response, "Location"), None));
+        assert_eq!(response_json(&mut members)["name"], "Office");
+    }
+
+    #[test]
+    fn metrics_are_valid_prometheus_text()
+    {
+        let app = new_app();
+        app_group(&app, 2);
+        let mut response =
+        {
+            let _env = default_env();
+            respond(&app, new_request(Method::Get, "/metrics", None))
+        };
+        assert_eq!(response.status() as u16, 200);
+        let text = futures::executor::block_on(response.body_string()).unwrap();
+
+        let mut described: HashMap<&str, (bool, bool)> = HashMap::new();
+        let mut series: HashSet<&str> = HashSet::new();
+        let mut values: HashMap<&str, f64> = HashMap::new();
+        for line in text.lines()
+        {
+            if let Some(rest) = line.strip_prefix("# HELP ")
+            {
+                let name = rest.split(' ').next().unwrap();
+                assert!(described.insert(name, (true, false)).is_none(), "second HELP for {}", name);
+            }
+            else if let Some(rest) = line.strip_prefix("# TYPE ")
+            {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert!(kind == "gauge" || kind == "counter", "{}", line);
+                assert_eq!(described.get(name), Some(&(true, false)), "TYPE without HELP for {}", name);
+                described.insert(name, (true, true));
+            }
+            else
+            {
+                let (key, value) = line.rsplit_once(' ').unwrap();
+                let name = key.split('{').next().unwrap();
+                assert_eq!(described.get(name), Some(&(true, true)), "sample before HELP and TYPE: {}", line);
+                assert!(series.insert(key), "duplicate series {}", key);
+                values.insert(key, value.parse().unwrap());
+            }
+        }
+        // Счетчики общие для всех тестов, поэтому сравниваются снизу
+        assert_eq!(values["santa_users"], 2.0);
+        assert_eq!(values["santa_groups"], 1.0);
+        assert_eq!(values["santa_closed_groups"], 0.0);
+        assert!(values[r#"santa_http_requests_total{method="POST",route="/user/create",status="201"}"#] >= 2.0);
+        assert!(values[r#"santa_http_requests_total{method="POST",route="/group/create",status="201"}"#] >= 1.0);
+    }
+
+    #[test]
+    fn assignments_are_signed_with_the_configured_key()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 3);
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        assert_eq!(post(&app, "/group/secret_santa", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        let path = format!("/group/assignments?group_id={}&admin_id={}", group_id, users[0]);
+        assert_eq!(get(&app, &path), (503, error_value("signing key is not configured")));
+
+        let _env = set_env(&[("SANTA_SIGNING_KEY", "test key")]);
+        let (status, body) = send_with_env(&app, Method::Get, &path, None);
+        assert_eq!(status, 200);
+        let signature = base64::decode(body["signature"].as_str().unwrap()).unwrap();
+        let verifies = |manifest: &str|
+        {
+            let mut mac = Hmac::<Sha256>::new_varkey(b"test key").unwrap();
+            mac.update(manifest.as_bytes());
+            mac.verify(&signature).is_ok()
+        };
+        let manifest = body["manifest"].as_str().unwrap();
+        assert!(verifies(manifest));
+
+        let mut parsed: Value = serde_json::from_str(manifest).unwrap();
+        assert_eq!(parsed["group_id"], group_id);
+        assert_eq!(parsed["members"], json!(users));
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), manifest);
+        // Подмена одного назначения делает подпись недействительной
+        let giver = users[0].to_string();
+        let other = users.iter().find(|user_id| **user_id != users[0] && parsed["assignments"][&giver] != **user_id).unwrap();
+        parsed["assignments"][&giver] = json!(other);
+        assert!(!verifies(&serde_json::to_string(&parsed).unwrap()));
+    }
+
+    #[test]
+    fn reveal_token_works_once()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 3);
+        assert_eq!(post(&app, "/group/close", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        assert_eq!(post(&app, "/group/secret_santa", json!({"admin_id": users[0], "group_id": group_id})).0, 200);
+        let (status, body) = post(&app, "/group/reveal_token", json!({"admin_id": users[0], "group_id": group_id, "user_id": users[1]}));
+        assert_eq!(status, 200);
+        let path = format!("/reveal?token={}", body["token"].as_str().unwrap());
+
+        let (status, body) = get(&app, &path);
+        assert_eq!(status, 200);
+        assert_eq!(body["group_id"], group_id);
+        let (_, target) = get(&app, &format!("/group/target_by_id/{}/{}", users[1], group_id));
+        assert_eq!(body["cysh_for_id"], target["cysh_for_id"]);
+        assert_eq!(get(&app, &path), (404, error_value("no such token")));
+    }
+
+    #[test]
+    fn poisoned_state_still_serves_requests()
+    {
+        let state = Arc::new(RwLock::new(DataBase::new()));
+        let app = build_app(state.clone());
+        let user_id = post(&app, "/user/create", json!({"name": "Ann"})).1["id"].clone();
+        let poisoner = state.clone();
+        let panicked = std::thread::spawn(move ||
+        {
+            let _guard = poisoner.write().unwrap();
+            panic!("handler panicked while holding the state");
+        }).join();
+        assert!(panicked.is_err());
+        assert!(state.is_poisoned());
+
+        assert_eq!(get(&app, &format!("/user/{}", user_id)).1["name"], "Ann");
+        assert_eq!(post(&app, "/user/create", json!({"name": "Bob"})).0, 201);
+        assert_eq!(get(&app, "/users").1["total"], 2);
+    }
+
+    #[test]
+    fn target_by_id_rejects_bad_ids()
+    {
+        let app = new_app();
+        let (group_id, users) = app_group(&app, 2);
+        let bad_user = error_value("Wrong format user id");
+        let bad_group = error_value("Wrong format group id");
+        assert_eq!(get(&app, &format!("/group/target_by_id/ann/{}", group_id)), (400, bad_user.clone()));
+        assert_eq!(get(&app, &format!("/group/target_by_id/{}/{}", Id::MAX as u64 + 1, group_id)), (400, bad_user.clone()));
+        assert_eq!(get(&app, &format!("/group/target_by_id/%D9%A1/{}", group_id)), (400, bad_user));
+        assert_eq!(get(&app, &format!("/group/target_by_id/{}/office", users[0])), (400, bad_group.clone()));
+        assert_eq!(get(&app, &format!("/group/target_by_id/{}/99999999999999999999", users[0])), (400, bad_group));
+        assert_eq!(get(&app, &format!("/group/target_by_id/{}/{}", users[0], group_id)), (400, error_value("secret santa has not started yet")));
+        assert_eq!(get(&app, &format!("/user/{}", Id::MAX as u64 + 1)), (400, error_value("Wrong format user id")));
+    }
+}